@@ -0,0 +1,183 @@
+use crate::httpclient::{env_or, fetch_text};
+use crate::scalars::Long;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use reqwest::header::HeaderMap;
+use rusty_pipe::youtube_extractor::error::ParsingError;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    static BYPASS: bool;
+}
+
+/// Runs `f` with the page cache skipped for every download it makes.
+pub async fn bypassing<F: Future>(bypass: bool, f: F) -> F::Output {
+    BYPASS.scope(bypass, f).await
+}
+
+/// Whether the current task skips the page cache.
+pub fn bypassed() -> bool {
+    BYPASS.try_with(|b| *b).unwrap_or(false)
+}
+
+/// How long a page may be served from cache, picked from the kind of endpoint it came from.
+pub fn ttl_for(url: &str) -> Duration {
+    let secs = if url.contains("/feed/trending") {
+        env_or("CACHE_TTL_TRENDING_SECS", 600)
+    } else if url.contains("/watch") || url.contains("get_video_info") {
+        env_or("CACHE_TTL_WATCH_SECS", 30)
+    } else if url.contains("/results") || url.contains("search") {
+        env_or("CACHE_TTL_SEARCH_SECS", 120)
+    } else if url.contains("/playlist") {
+        env_or("CACHE_TTL_PLAYLIST_SECS", 300)
+    } else if url.contains("/channel/") || url.contains("/user/") || url.contains("/c/") {
+        env_or("CACHE_TTL_CHANNEL_SECS", 300)
+    } else if url.ends_with(".js") {
+        env_or("CACHE_TTL_PLAYER_SECS", 6 * 3600)
+    } else {
+        env_or("CACHE_TTL_DEFAULT_SECS", 60)
+    };
+    Duration::from_secs(secs)
+}
+
+pub(crate) fn cache_key(url: &str, headers: &HeaderMap) -> String {
+    let mut pairs: Vec<String> = headers
+        .iter()
+        .map(|(k, v)| format!("{}:{}", k, v.to_str().unwrap_or_default()))
+        .collect();
+    pairs.sort();
+    format!("{}\n{}", url, pairs.join("\n"))
+}
+
+struct CachedPage {
+    body: String,
+    expires: Instant,
+}
+
+pub struct PageCache {
+    entries: LruCache<String, CachedPage>,
+    bytes: usize,
+    max_bytes: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl PageCache {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        PageCache {
+            entries: LruCache::new(max_entries),
+            bytes: 0,
+            max_bytes,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<String> {
+        let fresh = match self.entries.get(key) {
+            Some(page) if page.expires > Instant::now() => Some(page.body.clone()),
+            Some(_) => None,
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+        match fresh {
+            Some(body) => {
+                self.hits += 1;
+                Some(body)
+            }
+            None => {
+                self.remove(key);
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(page) = self.entries.pop(key) {
+            self.bytes -= page.body.len();
+        }
+    }
+
+    fn insert(&mut self, key: String, body: String, ttl: Duration) {
+        if body.len() > self.max_bytes {
+            return;
+        }
+        self.remove(&key);
+        while self.entries.len() >= self.entries.cap() || self.bytes + body.len() > self.max_bytes {
+            match self.entries.pop_lru() {
+                Some((_, page)) => self.bytes -= page.body.len(),
+                None => break,
+            }
+        }
+        self.bytes += body.len();
+        self.entries.put(
+            key,
+            CachedPage {
+                body,
+                expires: Instant::now() + ttl,
+            },
+        );
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let hits = Long::from(self.hits);
+        let misses = Long::from(self.misses);
+        let entries = Long::from(self.entries.len() as u64);
+        let bytes = Long::from(self.bytes as u64);
+        CacheStats {
+            hits: hits.saturating_i32(),
+            hits_v2: hits,
+            misses: misses.saturating_i32(),
+            misses_v2: misses,
+            entries: entries.saturating_i32(),
+            entries_v2: entries,
+            bytes: bytes.saturating_i32(),
+            bytes_v2: bytes,
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref PAGE_CACHE: Mutex<PageCache> = Mutex::new(PageCache::new(
+        env_or("CACHE_MAX_ENTRIES", 512),
+        env_or("CACHE_MAX_BYTES", 64 * 1024 * 1024),
+    ));
+}
+
+/// Serves `url` from the page cache, downloading and storing it on a miss.
+pub async fn get_or_fetch(url: &str, headers: HeaderMap) -> Result<String, ParsingError> {
+    if bypassed() {
+        return fetch_text(url, headers).await;
+    }
+    let key = cache_key(url, &headers);
+    if let Some(body) = PAGE_CACHE.lock().unwrap().get(&key) {
+        return Ok(body);
+    }
+    let body = fetch_text(url, headers).await?;
+    PAGE_CACHE
+        .lock()
+        .unwrap()
+        .insert(key, body.clone(), ttl_for(url));
+    Ok(body)
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct CacheStats {
+    #[graphql(deprecation = "Use hitsV2")]
+    pub hits: i32,
+    pub hits_v2: Long,
+    #[graphql(deprecation = "Use missesV2")]
+    pub misses: i32,
+    pub misses_v2: Long,
+    #[graphql(deprecation = "Use entriesV2")]
+    pub entries: i32,
+    pub entries_v2: Long,
+    #[graphql(deprecation = "Use bytesV2")]
+    pub bytes: i32,
+    pub bytes_v2: Long,
+}
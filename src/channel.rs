@@ -1,25 +1,28 @@
+use crate::counts::count;
+use crate::errors::field_error;
+use crate::scalars::Long;
 use crate::search::VideoResult;
 use crate::Context;
 use crate::Thumbnail;
 use juniper::FieldError;
-use rusty_pipe::utils::utils::fix_thumbnail_url;
 use rusty_pipe::youtube_extractor::channel_extractor::YTChannelExtractor;
+use std::sync::Arc;
 
 pub struct Channel {
-    pub extractor: YTChannelExtractor,
+    pub extractor: Arc<YTChannelExtractor>,
 }
 
 #[juniper::graphql_object(Context = Context)]
 impl Channel {
     fn name(&self) -> Result<String, FieldError> {
-        Ok(self.extractor.get_name()?)
+        Ok(self.extractor.get_name().map_err(field_error)?)
     }
 
-    fn avatars(&self) -> Result<Vec<Thumbnail>, FieldError> {
+    fn avatars(&self, context: &Context) -> Result<Vec<Thumbnail>, FieldError> {
         let mut avatars = vec![];
-        for avatar in self.extractor.get_avatars()? {
+        for avatar in self.extractor.get_avatars().map_err(field_error)? {
             avatars.push(Thumbnail {
-                url: fix_thumbnail_url(&avatar.url),
+                url: context.image_url(&avatar.url),
                 width: avatar.width as i32,
                 height: avatar.height as i32,
             })
@@ -27,11 +30,11 @@ impl Channel {
         Ok(avatars)
     }
 
-    fn banners(&self) -> Result<Vec<Thumbnail>, FieldError> {
+    fn banners(&self, context: &Context) -> Result<Vec<Thumbnail>, FieldError> {
         let mut banners = vec![];
-        for banner in self.extractor.get_banners()? {
+        for banner in self.extractor.get_banners().map_err(field_error)? {
             banners.push(Thumbnail {
-                url: fix_thumbnail_url(&banner.url),
+                url: context.image_url(&banner.url),
                 width: banner.width as i32,
                 height: banner.height as i32,
             })
@@ -40,29 +43,35 @@ impl Channel {
     }
 
     fn next_page_url(&self) -> Result<Option<String>, FieldError> {
-        Ok(self.extractor.get_next_page_url()?)
+        Ok(self.extractor.get_next_page_url().map_err(field_error)?)
     }
 
-    fn videos(&self) -> Result<Vec<VideoResult>, FieldError> {
+    fn videos(&self, context: &Context) -> Result<Vec<VideoResult>, FieldError> {
         let mut videos = vec![];
-        for vid in self.extractor.get_videos()? {
+        for vid in self.extractor.get_videos().map_err(field_error)? {
+            let duration = vid.get_duration().ok();
+            let view_count = count(&vid.video_info, &["viewCountText", "shortViewCountText"])
+                .or_else(|| vid.get_view_count().ok().map(Long::from));
             videos.push(VideoResult {
-                name: vid.get_name()?,
-                video_id: vid.video_id()?,
+                name: vid.get_name().map_err(field_error)?,
+                video_id: vid.video_id().map_err(field_error)?,
                 is_ad: vid.is_ad().unwrap_or(false),
                 is_premium_video: vid.is_premium_video().unwrap_or(false),
-                url: vid.get_url()?,
+                url: vid.get_url().map_err(field_error)?,
                 is_live: vid.is_live().unwrap_or(false),
-                duration: vid.get_duration().ok(),
+                duration,
+                duration_v2: duration.map(Long::from),
                 uploader_name: vid.get_uploader_name().ok(),
                 uploader_url: vid.get_uploader_url().ok(),
                 upload_date: vid.get_textual_upload_date().ok(),
-                view_count: vid.get_view_count().ok(),
+                view_count: view_count.map(Long::saturating_i32),
+                view_count_v2: view_count,
                 thumbnail: vid
-                    .get_thumbnails()?
+                    .get_thumbnails()
+                    .map_err(field_error)?
                     .iter()
                     .map(|f| Thumbnail {
-                        url: fix_thumbnail_url(&f.url),
+                        url: context.image_url(&f.url),
                         width: f.width as i32,
                         height: f.height as i32,
                     })
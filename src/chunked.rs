@@ -0,0 +1,301 @@
+use crate::errors::{error_response, ErrorCode};
+use crate::httpclient::env_or;
+use crate::vidproxy::{connection_tokens, fetch, relayable, FORWARDED_REQUEST_HEADERS};
+use futures::future::{abortable, AbortHandle, Aborted};
+use futures::StreamExt;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use warp::http::header::{self, HeaderMap, HeaderValue};
+use warp::http::{Method, Response, StatusCode};
+use warp::hyper::body::Sender;
+use warp::hyper::Body;
+
+/// The byte range of a stream a `/vid` request is served, fetched upstream in chunks of
+/// `VID_CHUNK_SIZE` bytes.
+#[derive(Clone, Copy)]
+pub struct Plan {
+    start: u64,
+    end: u64,
+    total: u64,
+    partial: bool,
+    chunk_size: u64,
+}
+
+impl Plan {
+    fn chunk(&self, start: u64) -> (u64, u64) {
+        (start, (start + self.chunk_size - 1).min(self.end))
+    }
+
+    pub fn first_chunk(&self) -> (u64, u64) {
+        self.chunk(self.start)
+    }
+
+    /// The chunks after the first one.
+    fn rest(&self) -> impl Iterator<Item = (u64, u64)> {
+        let plan = *self;
+        (1..)
+            .map(move |n| plan.start + n * plan.chunk_size)
+            .take_while(move |start| *start <= plan.end)
+            .map(move |start| plan.chunk(start))
+    }
+
+    /// The client's forwarded headers with `Range` replaced by one chunk. `If-Range` only
+    /// makes sense for a range the client asked for, so it is dropped otherwise: a
+    /// changed stream would turn a chunk into the whole stream.
+    pub fn chunk_headers(&self, headers: &HeaderMap, (start, end): (u64, u64)) -> HeaderMap {
+        let mut forwarded = HeaderMap::new();
+        for name in FORWARDED_REQUEST_HEADERS.iter() {
+            if let Some(value) = headers.get(name) {
+                forwarded.insert(name, value.clone());
+            }
+        }
+        if !self.partial {
+            forwarded.remove(header::IF_RANGE);
+        }
+        if let Ok(range) = HeaderValue::from_str(&format!("bytes={}-{}", start, end)) {
+            forwarded.insert(header::RANGE, range);
+        }
+        forwarded
+    }
+}
+
+/// Plans a chunked response for the client's `Range`, if any. Returns `None`, leaving
+/// the request to a single upstream fetch, when chunking is disabled, the stream length
+/// is unknown, or the range is one upstream has to judge: multiple ranges, or ones that
+/// cannot be satisfied.
+pub fn plan(headers: &HeaderMap, content_length: Option<&str>) -> Option<Plan> {
+    let chunk_size: u64 = env_or("VID_CHUNK_SIZE", 10 * 1024 * 1024);
+    let total: u64 = content_length?.parse().ok()?;
+    if chunk_size == 0 || total == 0 {
+        return None;
+    }
+    let (start, end, partial) = match headers.get(header::RANGE) {
+        None => (0, total - 1, false),
+        Some(range) => {
+            let spec = range.to_str().ok()?.trim().strip_prefix("bytes=")?;
+            if spec.contains(',') {
+                return None;
+            }
+            let mut bounds = spec.splitn(2, '-').map(str::trim);
+            let (first, last) = (bounds.next()?, bounds.next()?);
+            if first.is_empty() {
+                let suffix: u64 = last.parse().ok()?;
+                (total.saturating_sub(suffix), total - 1, true)
+            } else if last.is_empty() {
+                (first.parse().ok()?, total - 1, true)
+            } else {
+                let last: u64 = last.parse().ok()?;
+                (first.parse().ok()?, last.min(total - 1), true)
+            }
+        }
+    };
+    if start > end {
+        return None;
+    }
+    Some(Plan {
+        start,
+        end,
+        total,
+        partial,
+        chunk_size,
+    })
+}
+
+struct Chunker {
+    proxy: usize,
+    url: String,
+    headers: HeaderMap,
+    plan: Plan,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl Chunker {
+    /// Waits before retry `attempt`, twice as long as before the previous one.
+    async fn back_off(&self, attempt: u32) {
+        tokio::time::delay_for(self.backoff * 2u32.pow(attempt.min(16) - 1)).await;
+    }
+
+    /// Requests one chunk, retrying failed requests up to `VID_CHUNK_RETRIES` times.
+    async fn fetch(&self, range: (u64, u64)) -> Result<reqwest::Response, String> {
+        let headers = self.plan.chunk_headers(&self.headers, range);
+        let mut attempt = 0;
+        loop {
+            let failure = match fetch(Method::GET, self.proxy, &self.url, &headers).await {
+                Ok(resp) if resp.status() == StatusCode::PARTIAL_CONTENT => return Ok(resp),
+                Ok(resp) => format!("upstream answered {}", resp.status()),
+                Err(resp) => format!("upstream failed with {}", resp.status()),
+            };
+            attempt += 1;
+            if attempt > self.retries {
+                return Err(format!("chunk {}-{} failed: {}", range.0, range.1, failure));
+            }
+            self.back_off(attempt).await;
+        }
+    }
+
+    /// Forwards a chunk's body, resuming from the last byte sent if upstream drops it.
+    async fn send(
+        &self,
+        mut resp: reqwest::Response,
+        range: (u64, u64),
+        sender: &mut Sender,
+    ) -> Result<(), String> {
+        let mut offset = range.0;
+        let mut attempt = 0;
+        loop {
+            let mut body = resp.bytes_stream();
+            let mut failure = "chunk ended early".to_owned();
+            while let Some(piece) = body.next().await {
+                match piece {
+                    Ok(piece) => {
+                        offset += piece.len() as u64;
+                        sender
+                            .send_data(piece)
+                            .await
+                            .map_err(|_| "client went away".to_owned())?;
+                    }
+                    Err(e) => {
+                        failure = e.to_string();
+                        break;
+                    }
+                }
+            }
+            if offset > range.1 {
+                return Ok(());
+            }
+            attempt += 1;
+            if attempt > self.retries {
+                return Err(failure);
+            }
+            self.back_off(attempt).await;
+            resp = self.fetch((offset, range.1)).await?;
+        }
+    }
+}
+
+/// A chunk requested ahead of time, whose request is aborted if it is dropped unused,
+/// such as when the client goes away.
+struct Prefetch {
+    range: (u64, u64),
+    request: JoinHandle<Result<Result<reqwest::Response, String>, Aborted>>,
+    abort: AbortHandle,
+}
+
+impl Prefetch {
+    fn start(chunker: Arc<Chunker>, range: (u64, u64)) -> Self {
+        let (request, abort) = abortable(async move { chunker.fetch(range).await });
+        Prefetch {
+            range,
+            request: tokio::spawn(request),
+            abort,
+        }
+    }
+
+    async fn response(mut self) -> Result<reqwest::Response, String> {
+        match (&mut self.request).await {
+            Ok(Ok(resp)) => resp,
+            Ok(Err(Aborted)) => Err("prefetch aborted".to_owned()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        self.abort.abort();
+    }
+}
+
+/// Sends every chunk of `plan` in order, keeping `VID_PREFETCH_CHUNKS` requests ahead
+/// of the one being sent. Sending waits for the client, so a slow reader holds up the
+/// upstream reads instead of filling memory.
+async fn pump(
+    chunker: Arc<Chunker>,
+    first: reqwest::Response,
+    plan: Plan,
+    sender: &mut Sender,
+) -> Result<(), String> {
+    let prefetch: usize = env_or("VID_PREFETCH_CHUNKS", 1);
+    let mut upcoming = plan.rest();
+    let mut pending: VecDeque<Prefetch> = VecDeque::new();
+    let mut current = (first, plan.first_chunk());
+    loop {
+        while pending.len() < prefetch {
+            match upcoming.next() {
+                Some(range) => pending.push_back(Prefetch::start(chunker.clone(), range)),
+                None => break,
+            }
+        }
+        chunker.send(current.0, current.1, sender).await?;
+        current = match pending.pop_front() {
+            Some(next) => {
+                let range = next.range;
+                (next.response().await?, range)
+            }
+            None => match upcoming.next() {
+                Some(range) => (chunker.fetch(range).await?, range),
+                None => return Ok(()),
+            },
+        };
+    }
+}
+
+/// Answers a `/vid` request from its first upstream chunk, fetching the rest in the
+/// background as the client reads.
+pub fn respond(
+    first: reqwest::Response,
+    proxy: usize,
+    headers: &HeaderMap,
+    plan: Plan,
+    mime_type: &str,
+) -> Response<Body> {
+    let mut builder = Response::builder().status(if plan.partial {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
+    });
+    let connection_tokens = connection_tokens(first.headers());
+    for (name, value) in first.headers() {
+        if relayable(name, &connection_tokens)
+            && name != header::CONTENT_LENGTH
+            && name != header::CONTENT_RANGE
+        {
+            builder = builder.header(name, value);
+        }
+    }
+    if !first.headers().contains_key(header::CONTENT_TYPE) {
+        builder = builder.header(header::CONTENT_TYPE, mime_type);
+    }
+    builder = builder.header(header::ACCEPT_RANGES, "bytes").header(
+        header::CONTENT_LENGTH,
+        (plan.end - plan.start + 1).to_string(),
+    );
+    if plan.partial {
+        builder = builder.header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", plan.start, plan.end, plan.total),
+        );
+    }
+
+    let chunker = Arc::new(Chunker {
+        proxy,
+        url: first.url().to_string(),
+        headers: headers.clone(),
+        plan,
+        retries: env_or("VID_CHUNK_RETRIES", 2),
+        backoff: Duration::from_millis(env_or("VID_CHUNK_BACKOFF_MS", 250)),
+    });
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        if let Err(e) = pump(chunker, first, plan, &mut sender).await {
+            log::warn!("chunked transfer stopped: {}", e);
+            sender.abort();
+        }
+    });
+    builder
+        .body(body)
+        .unwrap_or_else(|e| error_response(ErrorCode::UpstreamError, e))
+}
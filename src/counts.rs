@@ -0,0 +1,121 @@
+use crate::scalars::Long;
+use serde_json::{Map, Value};
+
+/// Parses a count the way YouTube renders it: `3,000,000,000 views`, `1.2M subscribers`,
+/// `15B views` or `No views`. rusty_pipe's getters return `i32`, which wraps for the most
+/// viewed videos, so the `V2` fields read the text instead.
+pub fn parse_count(text: &str) -> Option<i64> {
+    let text = text.trim();
+    if text.starts_with("No ") {
+        return Some(0);
+    }
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let rest = &text[start..];
+    let end = rest
+        .find(|c: char| !(c.is_ascii_digit() || c == ',' || c == '.' || c == '\u{a0}'))
+        .unwrap_or(rest.len());
+    let number = rest[..end].trim_end_matches(&[',', '.', '\u{a0}'][..]);
+    let multiplier = match rest[end..].chars().next() {
+        Some('K') | Some('k') => 1_000.0,
+        Some('M') => 1_000_000.0,
+        Some('B') => 1_000_000_000.0,
+        _ => {
+            let digits: String = number.chars().filter(char::is_ascii_digit).collect();
+            return digits.parse().ok();
+        }
+    };
+    let mantissa: f64 = number
+        .replace(',', ".")
+        .replace('\u{a0}', "")
+        .parse()
+        .ok()?;
+    Some((mantissa * multiplier).round() as i64)
+}
+
+/// The text of a YouTube text object, either `{"simpleText": ...}` or
+/// `{"runs": [{"text": ...}, ...]}`. Plain strings and numbers are taken as they are.
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Object(object) => match object.get("simpleText") {
+            Some(Value::String(text)) => Some(text.clone()),
+            _ => object.get("runs")?.as_array().map(|runs| {
+                runs.iter()
+                    .filter_map(|run| run.get("text")?.as_str())
+                    .collect()
+            }),
+        },
+        _ => None,
+    }
+}
+
+/// The first of `keys` in a renderer that holds a count.
+pub fn count(renderer: &Map<String, Value>, keys: &[&str]) -> Option<Long> {
+    keys.iter()
+        .filter_map(|key| renderer.get(*key))
+        .filter_map(text)
+        .find_map(|text| parse_count(&text))
+        .map(Long)
+}
+
+/// The `key` count of `videoDetails` on a watch page, such as `viewCount`.
+pub fn watch_page_count(page: &str, key: &str) -> Option<Long> {
+    let details = page.find("\"videoDetails\":")?;
+    let marker = format!("\"{}\":\"", key);
+    let start = details + page[details..].find(&marker)? + marker.len();
+    let end = start + page[start..].find('"')?;
+    page[start..end].parse().ok().map(Long)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn counts_past_i32() {
+        assert_eq!(parse_count("3,000,000,000 views"), Some(3_000_000_000));
+        assert_eq!(parse_count("15B views"), Some(15_000_000_000));
+        assert_eq!(parse_count("2.3B views"), Some(2_300_000_000));
+    }
+
+    #[test]
+    fn short_and_plain_counts() {
+        assert_eq!(parse_count("1.2M subscribers"), Some(1_200_000));
+        assert_eq!(parse_count("950K views"), Some(950_000));
+        assert_eq!(parse_count("12 videos"), Some(12));
+        assert_eq!(parse_count("1 view"), Some(1));
+        assert_eq!(parse_count("No views"), Some(0));
+        assert_eq!(parse_count("views"), None);
+    }
+
+    #[test]
+    fn renderer_text() {
+        let renderer = json!({
+            "viewCountText": {"simpleText": "2,147,483,648 views"},
+            "videoCountText": {"runs": [{"text": "1,024"}, {"text": " videos"}]},
+            "videoCount": "37",
+        });
+        let renderer = renderer.as_object().unwrap();
+        assert_eq!(
+            count(renderer, &["viewCountText"]),
+            Some(Long(2_147_483_648))
+        );
+        assert_eq!(count(renderer, &["videoCountText"]), Some(Long(1024)));
+        assert_eq!(
+            count(renderer, &["subscriberCountText", "videoCount"]),
+            Some(Long(37))
+        );
+    }
+
+    #[test]
+    fn watch_page_view_count() {
+        let page = r#"{"videoDetails":{"videoId":"x","viewCount":"8123456789","author":"a"}}"#;
+        assert_eq!(
+            watch_page_count(page, "viewCount"),
+            Some(Long(8_123_456_789))
+        );
+        assert_eq!(watch_page_count("{}", "viewCount"), None);
+    }
+}
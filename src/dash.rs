@@ -0,0 +1,244 @@
+use crate::errors::{error_response, parsing_error_response, ErrorCode};
+use crate::serverrunner::{Context, Extractions};
+use crate::streaminfo::{label_fps, split_mime_type};
+use rusty_pipe::downloader_trait::Downloader;
+use rusty_pipe::youtube_extractor::stream_extractor::StreamItem as ExtractedStream;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use warp::http::header::{self, HeaderMap};
+use warp::http::Response;
+use warp::hyper::Body;
+
+pub struct ByteRange {
+    pub start: String,
+    pub end: String,
+}
+
+impl ByteRange {
+    pub fn as_dash(&self) -> String {
+        format!("{}-{}", self.start, self.end)
+    }
+}
+
+/// The parts of an extracted adaptive format a manifest describes. The ranges point at
+/// the initialization segment and the segment index inside the stream.
+#[allow(non_snake_case)]
+pub struct AdaptiveStream {
+    pub itag: u32,
+    pub mimeType: String,
+    pub bitrate: u32,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: Option<u32>,
+    pub audioChannels: Option<u32>,
+    pub audioSampleRate: Option<String>,
+    pub approxDurationMs: Option<String>,
+    pub contentLength: Option<String>,
+    pub initRange: Option<ByteRange>,
+    pub indexRange: Option<ByteRange>,
+}
+
+impl AdaptiveStream {
+    /// Splits `video/mp4; codecs="avc1.640028"` into the container and codec list.
+    pub fn container_and_codecs(&self) -> (&str, &str) {
+        split_mime_type(&self.mimeType)
+    }
+}
+
+pub fn adaptive_stream(stream: ExtractedStream) -> AdaptiveStream {
+    AdaptiveStream {
+        itag: stream.itag as u32,
+        mimeType: stream.mimeType,
+        bitrate: stream.bitrate as u32,
+        width: stream.width.map(|width| width as u32),
+        height: stream.height.map(|height| height as u32),
+        fps: stream
+            .qualityLabel
+            .as_deref()
+            .and_then(label_fps)
+            .map(|fps| fps as u32),
+        audioChannels: stream.audioChannels.map(|channels| channels as u32),
+        audioSampleRate: stream.audioSampleRate,
+        approxDurationMs: stream.approxDurationMs,
+        contentLength: stream.contentLength,
+        initRange: stream.initRange.map(|range| ByteRange {
+            start: range.start,
+            end: range.end,
+        }),
+        indexRange: stream.indexRange.map(|range| ByteRange {
+            start: range.start,
+            end: range.end,
+        }),
+    }
+}
+
+pub fn adaptive_streams(streams: Vec<ExtractedStream>) -> Vec<AdaptiveStream> {
+    streams.into_iter().map(adaptive_stream).collect()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// What a player has to be able to decode to switch between streams: the codec, and for
+/// VP9 the profile, as profile 2 is 10-bit and many decoders stop at profile 0. YouTube's
+/// short `vp9` and `vp9.2` are the profiles 0 and 2 of `vp09`.
+fn codec_family(codecs: &str) -> String {
+    let codec = codecs.split(',').next().unwrap_or_default().trim();
+    let mut parts = codec.split('.');
+    let family = parts.next().unwrap_or_default().to_lowercase();
+    match family.as_str() {
+        "vp9" => format!(
+            "vp09.{:02}",
+            parts.next().unwrap_or("0").parse::<u8>().unwrap_or(0)
+        ),
+        "vp09" => format!("vp09.{}", parts.next().unwrap_or("00")),
+        _ => family,
+    }
+}
+
+/// An on-demand MPD with one adaptation set per container and codec family, every
+/// representation addressing its `/vid` URL. Streams without init and index ranges
+/// cannot be described by `SegmentBase` and are left out; `None` when that leaves none.
+pub fn manifest(
+    context: &Context,
+    video_id: &str,
+    duration_secs: f64,
+    streams: &[AdaptiveStream],
+) -> Option<String> {
+    let mut sets: BTreeMap<(&str, String), Vec<&AdaptiveStream>> = BTreeMap::new();
+    for stream in streams {
+        if stream.initRange.is_some() && stream.indexRange.is_some() {
+            let (container, codecs) = stream.container_and_codecs();
+            sets.entry((container, codec_family(codecs)))
+                .or_default()
+                .push(stream);
+        }
+    }
+    if sets.is_empty() {
+        return None;
+    }
+
+    let mut mpd = String::new();
+    let _ = writeln!(mpd, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(
+        mpd,
+        r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-on-demand:2011" type="static" minBufferTime="PT1.5S" mediaPresentationDuration="PT{:.3}S">"#,
+        duration_secs
+    );
+    let _ = writeln!(mpd, r#"  <Period duration="PT{:.3}S">"#, duration_secs);
+    for (id, ((container, _), representations)) in sets.iter().enumerate() {
+        let audio = container.starts_with("audio/");
+        let _ = writeln!(
+            mpd,
+            r#"    <AdaptationSet id="{}" mimeType="{}" subsegmentAlignment="true" subsegmentStartsWithSAP="1">"#,
+            id,
+            escape(container)
+        );
+        for stream in representations {
+            let (_, codecs) = stream.container_and_codecs();
+            let mut attributes = format!(
+                r#"id="{}" codecs="{}" bandwidth="{}""#,
+                stream.itag,
+                escape(codecs),
+                stream.bitrate
+            );
+            if let (Some(width), Some(height)) = (stream.width, stream.height) {
+                let _ = write!(attributes, r#" width="{}" height="{}""#, width, height);
+            }
+            if let Some(fps) = stream.fps {
+                let _ = write!(attributes, r#" frameRate="{}""#, fps);
+            }
+            if let Some(rate) = &stream.audioSampleRate {
+                let _ = write!(attributes, r#" audioSamplingRate="{}""#, escape(rate));
+            }
+            let _ = writeln!(mpd, "      <Representation {}>", attributes);
+            if audio {
+                let _ = writeln!(
+                    mpd,
+                    r#"        <AudioChannelConfiguration schemeIdUri="urn:mpeg:dash:23003:3:audio_channel_configuration:2011" value="{}"/>"#,
+                    stream.audioChannels.unwrap_or(2)
+                );
+            }
+            let _ = writeln!(
+                mpd,
+                "        <BaseURL>{}</BaseURL>",
+                escape(&context.proxy_url(video_id, stream.itag))
+            );
+            if let (Some(init), Some(index)) = (&stream.initRange, &stream.indexRange) {
+                let _ = writeln!(
+                    mpd,
+                    r#"        <SegmentBase indexRange="{}"><Initialization range="{}"/></SegmentBase>"#,
+                    index.as_dash(),
+                    init.as_dash()
+                );
+            }
+            let _ = writeln!(mpd, "      </Representation>");
+        }
+        let _ = writeln!(mpd, "    </AdaptationSet>");
+    }
+    let _ = writeln!(mpd, "  </Period>");
+    let _ = writeln!(mpd, "</MPD>");
+    Some(mpd)
+}
+
+/// Serves `/dash/{video_id}.mpd`.
+pub async fn dashhandle<D>(
+    file: String,
+    remote: Option<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, warp::Rejection>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    let video_id = match file.strip_suffix(".mpd") {
+        Some(video_id) => video_id,
+        None => return Err(warp::reject::not_found()),
+    };
+    let videx = match Extractions::<D>::get().video(video_id).await {
+        Ok(videx) => videx,
+        Err(e) => {
+            log::warn!("cant extract {} for dash: {}", video_id, e);
+            return Ok(parsing_error_response(e));
+        }
+    };
+    let mut streams = adaptive_streams(videx.get_video_only_stream().unwrap_or_default());
+    streams.append(&mut adaptive_streams(
+        videx.get_audio_streams().unwrap_or_default(),
+    ));
+    if streams.is_empty() {
+        return Ok(error_response(
+            ErrorCode::NotFound,
+            format!("no adaptive streams for {}", video_id),
+        ));
+    }
+    let duration_secs = streams
+        .iter()
+        .find_map(|stream| stream.approxDurationMs.as_ref()?.parse::<f64>().ok())
+        .map(|ms| ms / 1000.0)
+        .or_else(|| videx.get_length().ok().map(|secs| secs as f64))
+        .unwrap_or_default();
+
+    let context = Context::from_request(&headers, remote);
+    let mpd = match manifest(&context, video_id, duration_secs, &streams) {
+        Some(mpd) => mpd,
+        None => {
+            return Ok(error_response(
+                ErrorCode::Unavailable,
+                format!(
+                    "no stream of {} can be described by a dash manifest",
+                    video_id
+                ),
+            ))
+        }
+    };
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/dash+xml")
+        .body(Body::from(mpd))
+        .unwrap_or_else(|e| error_response(ErrorCode::UpstreamError, e)))
+}
@@ -0,0 +1,149 @@
+use crate::httpclient::env_or;
+use crate::jsruntime::JS_POOL;
+use crate::scalars::Long;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+
+/// Extracts the player version from a player script URL such as
+/// `https://www.youtube.com/s/player/4fbb4d5b/player_ias.vflset/en_US/base.js`.
+pub fn player_version(url: &str) -> Option<&str> {
+    let start = url.find("/s/player/")? + "/s/player/".len();
+    let version = url[start..].split('/').next()?;
+    if version.is_empty() {
+        None
+    } else {
+        Some(version)
+    }
+}
+
+/// Memoised signature and `n` parameter transformations. The deciphering script
+/// embeds both the player's transformation and the input value, so its digest
+/// identifies a result; entries are dropped whenever a new player version shows up.
+/// The transformation itself is kept loaded in the JS runtimes, see `eval_cached`.
+pub struct DecipherCache {
+    version: Option<String>,
+    entries: LruCache<String, String>,
+    hits: u64,
+    misses: u64,
+    invalidations: u64,
+}
+
+impl DecipherCache {
+    pub fn new(capacity: usize) -> Self {
+        DecipherCache {
+            version: None,
+            entries: LruCache::new(capacity),
+            hits: 0,
+            misses: 0,
+            invalidations: 0,
+        }
+    }
+
+    /// Records the player version seen in an upstream URL, invalidating on change.
+    pub fn observe(&mut self, url: &str) {
+        let version = match player_version(url) {
+            Some(version) => version,
+            None => return,
+        };
+        if self.version.as_deref() == Some(version) {
+            return;
+        }
+        if let Some(old) = &self.version {
+            log::info!(
+                "player changed from {} to {}, dropping {} deciphered values",
+                old,
+                version,
+                self.entries.len()
+            );
+            self.entries.clear();
+            self.invalidations += 1;
+        }
+        self.version = Some(version.to_owned());
+    }
+
+    pub fn stats(&self) -> DecipherStats {
+        let entries = Long::from(self.entries.len() as u64);
+        let hits = Long::from(self.hits);
+        let misses = Long::from(self.misses);
+        let invalidations = Long::from(self.invalidations);
+        DecipherStats {
+            player_version: self.version.clone(),
+            entries: entries.saturating_i32(),
+            entries_v2: entries,
+            hits: hits.saturating_i32(),
+            hits_v2: hits,
+            misses: misses.saturating_i32(),
+            misses_v2: misses,
+            invalidations: invalidations.saturating_i32(),
+            invalidations_v2: invalidations,
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref DECIPHER_CACHE: Mutex<DecipherCache> =
+        Mutex::new(DecipherCache::new(env_or("DECIPHER_CACHE_ENTRIES", 8192)));
+}
+
+/// Splits a deciphering script into the player's definitions and the final call that
+/// applies them to one input, such as `...;decrypt("AOq0QJ8w...")`.
+fn split_call(script: &str) -> Option<(&str, &str)> {
+    let end = script.trim_end().trim_end_matches(';');
+    let at = end.rfind(';')?;
+    let (prelude, call) = (&end[..=at], end[at + 1..].trim());
+    let name = &call[..call.find('(')?];
+    let is_call = call.ends_with(')')
+        && !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+    if is_call {
+        Some((prelude, call))
+    } else {
+        None
+    }
+}
+
+/// Evaluates a deciphering script, reusing the result of an identical earlier evaluation.
+/// The player's definitions stay loaded in the runtimes, so a new input for the same
+/// player version only evaluates the final call.
+pub fn eval_cached(script: &str) -> Result<String, String> {
+    let key = format!("{:x}", Sha256::digest(script.as_bytes()));
+    {
+        let mut cache = DECIPHER_CACHE.lock().unwrap();
+        if let Some(result) = cache.entries.get(&key).cloned() {
+            cache.hits += 1;
+            return Ok(result);
+        }
+        cache.misses += 1;
+    }
+    let result = match split_call(script) {
+        Some((prelude, call)) => JS_POOL.eval_with(prelude, call)?,
+        None => JS_POOL.eval(script)?,
+    };
+    DECIPHER_CACHE
+        .lock()
+        .unwrap()
+        .entries
+        .put(key, result.clone());
+    Ok(result)
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct DecipherStats {
+    pub player_version: Option<String>,
+    #[graphql(deprecation = "Use entriesV2")]
+    pub entries: i32,
+    pub entries_v2: Long,
+    #[graphql(deprecation = "Use hitsV2")]
+    pub hits: i32,
+    pub hits_v2: Long,
+    #[graphql(deprecation = "Use missesV2")]
+    pub misses: i32,
+    pub misses_v2: Long,
+    #[graphql(deprecation = "Use invalidationsV2")]
+    pub invalidations: i32,
+    pub invalidations_v2: Long,
+}
@@ -0,0 +1,141 @@
+use crate::ratelimit::{DownloadFailure, CIRCUIT_BREAKER};
+use juniper::{graphql_value, FieldError, Value};
+use rusty_pipe::youtube_extractor::error::ParsingError;
+use std::borrow::Borrow;
+use warp::http::{Response, StatusCode};
+use warp::hyper::Body;
+
+/// The reasons YouTube gives for not playing a video, as worded on the watch page.
+const PLAYABILITY_REASONS: [(&str, ErrorCode); 8] = [
+    ("Sign in to confirm your age", ErrorCode::AgeRestricted),
+    (
+        "may be inappropriate for some users",
+        ErrorCode::AgeRestricted,
+    ),
+    ("This video is private", ErrorCode::Unavailable),
+    ("This video has been removed", ErrorCode::Unavailable),
+    ("has been terminated", ErrorCode::Unavailable),
+    ("This video is no longer available", ErrorCode::Unavailable),
+    ("This video is not available", ErrorCode::Unavailable),
+    ("Video unavailable", ErrorCode::Unavailable),
+];
+
+/// Stable error codes reported in GraphQL `extensions.code` and mapped onto HTTP
+/// statuses by the media routes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ErrorCode {
+    NotFound,
+    Unavailable,
+    AgeRestricted,
+    RateLimited,
+    UpstreamError,
+    ParseError,
+    Unauthorized,
+    BadRequest,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::Unavailable => "UNAVAILABLE",
+            ErrorCode::AgeRestricted => "AGE_RESTRICTED",
+            ErrorCode::RateLimited => "RATE_LIMITED",
+            ErrorCode::UpstreamError => "UPSTREAM_ERROR",
+            ErrorCode::ParseError => "PARSE_ERROR",
+            ErrorCode::Unauthorized => "UNAUTHORIZED",
+            ErrorCode::BadRequest => "BAD_REQUEST",
+        }
+    }
+
+    /// Whether asking again later may succeed.
+    pub fn retryable(self) -> bool {
+        match self {
+            ErrorCode::RateLimited | ErrorCode::UpstreamError => true,
+            _ => false,
+        }
+    }
+
+    pub fn http_status(self) -> StatusCode {
+        match self {
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Unavailable => StatusCode::GONE,
+            ErrorCode::AgeRestricted => StatusCode::FORBIDDEN,
+            ErrorCode::RateLimited => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::UpstreamError => StatusCode::BAD_GATEWAY,
+            ErrorCode::ParseError => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::Unauthorized => StatusCode::FORBIDDEN,
+            ErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    pub fn from_parsing_error(err: &ParsingError) -> Self {
+        if let ParsingError::DownloadError { .. } = err {
+            return match DownloadFailure::of(err) {
+                Some(DownloadFailure::RateLimited) | Some(DownloadFailure::Status(429)) => {
+                    ErrorCode::RateLimited
+                }
+                Some(DownloadFailure::Status(404)) | Some(DownloadFailure::Status(410)) => {
+                    ErrorCode::NotFound
+                }
+                _ => ErrorCode::UpstreamError,
+            };
+        }
+        // The extractor reports an unplayable video with YouTube's reason as its only
+        // detail, so match the reasons YouTube gives rather than loose keywords.
+        let message = err.to_string();
+        PLAYABILITY_REASONS
+            .iter()
+            .find(|(reason, _)| message.contains(reason))
+            .map_or(ErrorCode::ParseError, |(_, code)| *code)
+    }
+
+    fn extensions(self) -> Value {
+        let code = self.as_str();
+        let retryable = self.retryable();
+        match CIRCUIT_BREAKER.retry_after() {
+            Some(wait) if self == ErrorCode::RateLimited => {
+                let retry_after = wait.as_secs() as i32 + 1;
+                graphql_value!({ "code": code, "retryable": retryable, "retryAfter": retry_after })
+            }
+            _ => graphql_value!({ "code": code, "retryable": retryable }),
+        }
+    }
+}
+
+/// Converts an extraction failure into a GraphQL error carrying its `ErrorCode`.
+pub fn field_error<E: Borrow<ParsingError>>(err: E) -> FieldError {
+    let err = err.borrow();
+    let code = ErrorCode::from_parsing_error(err);
+    FieldError::new(err.to_string(), code.extensions())
+}
+
+/// A GraphQL error for arguments the server cannot make sense of.
+pub fn bad_request(message: impl ToString) -> FieldError {
+    FieldError::new(message.to_string(), ErrorCode::BadRequest.extensions())
+}
+
+/// A plain-text HTTP error response for the media routes.
+pub fn error_response(code: ErrorCode, message: impl ToString) -> Response<Body> {
+    let mut builder = Response::builder()
+        .status(code.http_status())
+        .header("content-type", "text/plain")
+        .header("x-error-code", code.as_str());
+    if code == ErrorCode::RateLimited {
+        if let Some(wait) = CIRCUIT_BREAKER.retry_after() {
+            builder = builder.header("retry-after", (wait.as_secs() + 1).to_string());
+        }
+    }
+    builder
+        .body(Body::from(format!(
+            "{}: {}",
+            code.as_str(),
+            message.to_string()
+        )))
+        .unwrap()
+}
+
+pub fn parsing_error_response<E: Borrow<ParsingError>>(err: E) -> Response<Body> {
+    let err = err.borrow();
+    error_response(ErrorCode::from_parsing_error(err), err)
+}
@@ -0,0 +1,93 @@
+use crate::cache::{cache_key, get_or_fetch};
+use crate::httpclient::fetch_text;
+use lazy_static::lazy_static;
+use reqwest::header::HeaderMap;
+use rusty_pipe::youtube_extractor::error::ParsingError;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Where `DownloaderObj` gets its pages from, selected with `DOWNLOADER_MODE`
+/// (`live`, `record` or `replay`) and `FIXTURE_DIR`.
+pub enum DownloadMode {
+    Live,
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+impl DownloadMode {
+    pub fn from_env() -> Self {
+        let dir = PathBuf::from(std::env::var("FIXTURE_DIR").unwrap_or("fixtures".to_owned()));
+        match std::env::var("DOWNLOADER_MODE")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "record" => DownloadMode::Record(dir),
+            "replay" => DownloadMode::Replay(dir),
+            _ => DownloadMode::Live,
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref DOWNLOAD_MODE: DownloadMode = DownloadMode::from_env();
+}
+
+#[derive(Serialize, Deserialize)]
+struct Fixture {
+    url: String,
+    headers: BTreeMap<String, String>,
+    body: String,
+}
+
+fn fixture_path(dir: &Path, url: &str, headers: &HeaderMap) -> PathBuf {
+    let digest = Sha256::digest(cache_key(url, headers).as_bytes());
+    dir.join(format!("{:x}.json", digest))
+}
+
+/// Downloads `url` according to the configured `DownloadMode`.
+pub async fn download(url: &str, headers: HeaderMap) -> Result<String, ParsingError> {
+    match &*DOWNLOAD_MODE {
+        DownloadMode::Live => get_or_fetch(url, headers).await,
+        DownloadMode::Record(dir) => {
+            let path = fixture_path(dir, url, &headers);
+            let fixture = Fixture {
+                url: url.to_owned(),
+                headers: headers
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or_default().to_owned()))
+                    .collect(),
+                body: fetch_text(url, headers).await?,
+            };
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| e.to_string())?;
+            tokio::fs::write(
+                &path,
+                serde_json::to_string_pretty(&fixture).map_err(|e| e.to_string())?,
+            )
+            .await
+            .map_err(|e| format!("cant record fixture {}: {}", path.display(), e))?;
+            Ok(fixture.body)
+        }
+        DownloadMode::Replay(dir) => {
+            let path = fixture_path(dir, url, &headers);
+            let recorded = tokio::fs::read_to_string(&path).await.map_err(|e| {
+                log::error!("no recorded fixture for {} at {}", url, path.display());
+                ParsingError::DownloadError {
+                    cause: format!(
+                        "replay mode: no recorded fixture for {} at {}: {}",
+                        url,
+                        path.display(),
+                        e
+                    ),
+                }
+            })?;
+            let fixture: Fixture = serde_json::from_str(&recorded)
+                .map_err(|e| format!("corrupt fixture {}: {}", path.display(), e))?;
+            Ok(fixture.body)
+        }
+    }
+}
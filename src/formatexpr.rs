@@ -0,0 +1,510 @@
+use crate::selector::{rank, Candidate, Kind};
+use std::str::FromStr;
+
+/// Numeric fields a filter may compare: sizes in pixels, frames per second, bitrates in
+/// kbit/s, the sample rate in Hz and the file size in bytes.
+const NUMBER_KEYS: [&str; 10] = [
+    "height",
+    "width",
+    "fps",
+    "tbr",
+    "vbr",
+    "abr",
+    "asr",
+    "filesize",
+    "itag",
+    "format_id",
+];
+const TEXT_KEYS: [&str; 3] = ["ext", "vcodec", "acodec"];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Prefix,
+    Suffix,
+    Contains,
+}
+
+/// Longest operators first, so `<=` is not read as `<`.
+const OPS: [(&str, Op); 9] = [
+    ("!=", Op::Ne),
+    ("<=", Op::Le),
+    (">=", Op::Ge),
+    ("^=", Op::Prefix),
+    ("$=", Op::Suffix),
+    ("*=", Op::Contains),
+    ("=", Op::Eq),
+    ("<", Op::Lt),
+    (">", Op::Gt),
+];
+
+struct Filter {
+    key: String,
+    op: Op,
+    value: String,
+    /// Written `[height<=?1080]`: streams without the field pass.
+    optional: bool,
+}
+
+const ANY: [Kind; 3] = [Kind::Muxed, Kind::VideoOnly, Kind::AudioOnly];
+
+enum Base {
+    /// `best`/`worst` with the kinds of stream they pick from.
+    Ranked {
+        kinds: &'static [Kind],
+        worst: bool,
+    },
+    Itag(u32),
+}
+
+struct Format {
+    base: Base,
+    filters: Vec<Filter>,
+}
+
+/// A yt-dlp style format expression: alternatives separated by `/`, each one format or
+/// a video format and an audio format joined by `+`. Formats are `best`, `worst`,
+/// `bestvideo`, `worstvideo`, `bestaudio`, `worstaudio` (or `b`, `w`, `bv`, `wv`, `ba`,
+/// `wa`) or an itag, followed by filters such as `[height<=1080]` or `[vcodec^=av01]`.
+/// A `*` after a short name widens it to every stream with that track: `bv*` and `wv*`
+/// include muxed streams, `ba*` and `wa*` too, and `b*` and `w*` take any stream.
+pub struct FormatExpr {
+    alternatives: Vec<(Format, Option<Format>)>,
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> String {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at(&self, pos: usize, message: &str) -> String {
+        format!("{} at position {} of {:?}", message, pos, self.input)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_spaces(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_spaces();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take_while(&mut self, keep: impl Fn(char) -> bool) -> &'a str {
+        self.skip_spaces();
+        let rest = self.rest();
+        let len = rest.find(|c| !keep(c)).unwrap_or(rest.len());
+        self.pos += len;
+        rest[..len].trim_end()
+    }
+
+    fn expr(&mut self) -> Result<FormatExpr, String> {
+        let mut alternatives = vec![];
+        loop {
+            let first = self.format()?;
+            let second = if self.eat("+") {
+                Some(self.format()?)
+            } else {
+                None
+            };
+            alternatives.push((first, second));
+            if !self.eat("/") {
+                break;
+            }
+        }
+        self.skip_spaces();
+        if !self.rest().is_empty() {
+            return Err(self.error("unexpected input"));
+        }
+        Ok(FormatExpr { alternatives })
+    }
+
+    fn format(&mut self) -> Result<Format, String> {
+        self.skip_spaces();
+        let start = self.pos;
+        let name = self.take_while(|c| c.is_ascii_alphanumeric());
+        // No `eat`, as `bv *` is not `bv*`.
+        let widened = self.rest().starts_with('*');
+        if widened {
+            self.pos += 1;
+        }
+        let ranked = |kinds, worst| Base::Ranked { kinds, worst };
+        let base = match (name, widened) {
+            ("best", false) | ("b", false) => ranked(&[Kind::Muxed], false),
+            ("worst", false) | ("w", false) => ranked(&[Kind::Muxed], true),
+            ("b", true) => ranked(&ANY, false),
+            ("w", true) => ranked(&ANY, true),
+            ("bestvideo", false) | ("bv", false) => ranked(&[Kind::VideoOnly], false),
+            ("worstvideo", false) | ("wv", false) => ranked(&[Kind::VideoOnly], true),
+            ("bv", true) => ranked(&[Kind::Muxed, Kind::VideoOnly], false),
+            ("wv", true) => ranked(&[Kind::Muxed, Kind::VideoOnly], true),
+            ("bestaudio", false) | ("ba", false) => ranked(&[Kind::AudioOnly], false),
+            ("worstaudio", false) | ("wa", false) => ranked(&[Kind::AudioOnly], true),
+            ("ba", true) => ranked(&[Kind::Muxed, Kind::AudioOnly], false),
+            ("wa", true) => ranked(&[Kind::Muxed, Kind::AudioOnly], true),
+            ("", false) => ranked(&ANY, false),
+            (_, false) => match name.parse() {
+                Ok(itag) => Base::Itag(itag),
+                Err(_) => return Err(self.error_at(start, &format!("unknown format {:?}", name))),
+            },
+            (_, true) => return Err(self.error_at(start, &format!("unknown format \"{}*\"", name))),
+        };
+        let mut filters = vec![];
+        while self.eat("[") {
+            filters.push(self.filter()?);
+        }
+        if name.is_empty() && filters.is_empty() {
+            return Err(self.error("expected a format"));
+        }
+        Ok(Format { base, filters })
+    }
+
+    fn filter(&mut self) -> Result<Filter, String> {
+        self.skip_spaces();
+        let start = self.pos;
+        let key = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        let numeric = NUMBER_KEYS.contains(&key);
+        if !numeric && !TEXT_KEYS.contains(&key) {
+            return Err(self.error_at(start, &format!("unknown field {:?}", key)));
+        }
+        let op = match OPS.iter().find(|(token, _)| self.eat(token)) {
+            Some((_, op)) => *op,
+            None => return Err(self.error("expected a comparison")),
+        };
+        let allowed = if numeric {
+            ![Op::Prefix, Op::Suffix, Op::Contains].contains(&op)
+        } else {
+            [Op::Eq, Op::Ne, Op::Prefix, Op::Suffix, Op::Contains].contains(&op)
+        };
+        if !allowed {
+            return Err(self.error(&format!("{} cannot be compared that way", key)));
+        }
+        let optional = self.eat("?");
+        self.skip_spaces();
+        let start = self.pos;
+        let value = self.take_while(|c| c != ']');
+        if numeric && value.parse::<f64>().is_err() {
+            return Err(self.error_at(start, &format!("{} must be compared to a number", key)));
+        }
+        if !self.eat("]") {
+            return Err(self.error("expected ]"));
+        }
+        Ok(Filter {
+            key: key.to_owned(),
+            op,
+            value: value.to_lowercase(),
+            optional,
+        })
+    }
+}
+
+impl FromStr for FormatExpr {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, String> {
+        Parser { input, pos: 0 }.expr()
+    }
+}
+
+/// The video and audio codecs of a stream, `none` for the track it does not have.
+fn codecs(candidate: &Candidate) -> (String, String) {
+    let mut listed = candidate
+        .stream
+        .container_and_codecs()
+        .1
+        .split(',')
+        .map(|codec| codec.trim().to_lowercase());
+    let none = || "none".to_owned();
+    match candidate.kind {
+        Kind::AudioOnly => (none(), listed.next().unwrap_or_else(none)),
+        Kind::VideoOnly => (listed.next().unwrap_or_else(none), none()),
+        Kind::Muxed => (
+            listed.next().unwrap_or_else(none),
+            listed.next().unwrap_or_else(none),
+        ),
+    }
+}
+
+fn number(candidate: &Candidate, key: &str) -> Option<f64> {
+    let stream = &candidate.stream;
+    let kbps = stream.bitrate as f64 / 1000.0;
+    match key {
+        "height" => stream.height.map(f64::from),
+        "width" => stream.width.map(f64::from),
+        "fps" => stream.fps.map(f64::from),
+        "tbr" => Some(kbps),
+        "vbr" if candidate.kind == Kind::VideoOnly => Some(kbps),
+        "abr" if candidate.kind == Kind::AudioOnly => Some(kbps),
+        "asr" => stream.audioSampleRate.as_ref()?.parse().ok(),
+        "filesize" => stream.contentLength.as_ref()?.parse().ok(),
+        "itag" | "format_id" => Some(stream.itag as f64),
+        _ => None,
+    }
+}
+
+fn text(candidate: &Candidate, key: &str) -> Option<String> {
+    match key {
+        "ext" => {
+            let container = candidate.stream.container_and_codecs().0;
+            let ext = container.rsplit('/').next()?.to_lowercase();
+            // Audio only mp4 streams are m4a files.
+            if ext == "mp4" && candidate.kind == Kind::AudioOnly {
+                Some("m4a".to_owned())
+            } else {
+                Some(ext)
+            }
+        }
+        "vcodec" => Some(codecs(candidate).0),
+        "acodec" => Some(codecs(candidate).1),
+        _ => None,
+    }
+}
+
+impl Filter {
+    fn accepts(&self, candidate: &Candidate) -> bool {
+        if let Ok(wanted) = self.value.parse::<f64>() {
+            if let Some(have) = number(candidate, &self.key) {
+                return match self.op {
+                    Op::Eq => (have - wanted).abs() < f64::EPSILON,
+                    Op::Ne => (have - wanted).abs() >= f64::EPSILON,
+                    Op::Lt => have < wanted,
+                    Op::Le => have <= wanted,
+                    Op::Gt => have > wanted,
+                    Op::Ge => have >= wanted,
+                    _ => false,
+                };
+            }
+        }
+        match text(candidate, &self.key) {
+            Some(have) => match self.op {
+                Op::Eq => have == self.value,
+                Op::Ne => have != self.value,
+                Op::Prefix => have.starts_with(&self.value),
+                Op::Suffix => have.ends_with(&self.value),
+                Op::Contains => have.contains(&self.value),
+                _ => false,
+            },
+            None => self.optional,
+        }
+    }
+}
+
+impl Format {
+    fn pick<'a>(&self, candidates: &'a [Candidate]) -> Option<&'a Candidate> {
+        let mut matching = candidates
+            .iter()
+            .filter(|candidate| self.filters.iter().all(|filter| filter.accepts(candidate)));
+        match self.base {
+            Base::Itag(itag) => matching.find(|candidate| candidate.stream.itag == itag),
+            Base::Ranked { kinds, worst } => matching
+                .filter(|candidate| kinds.contains(&candidate.kind))
+                .min_by(|a, b| rank(a, b, worst)),
+        }
+    }
+}
+
+impl FormatExpr {
+    /// The first alternative every part of which matches a stream, as the video stream
+    /// (which may carry sound too) and the audio stream.
+    pub fn choose<'a>(
+        &self,
+        candidates: &'a [Candidate],
+    ) -> Option<(Option<&'a Candidate>, Option<&'a Candidate>)> {
+        self.alternatives.iter().find_map(|(first, second)| {
+            let first = first.pick(candidates)?;
+            let second = match second {
+                Some(second) => Some(second.pick(candidates)?),
+                None => None,
+            };
+            let audio = |c: &Candidate| c.kind == Kind::AudioOnly;
+            match second {
+                None if audio(first) => Some((None, Some(first))),
+                None => Some((Some(first), None)),
+                // Merging takes one stream with video and one with audio only.
+                Some(second) if !audio(first) && audio(second) => Some((Some(first), Some(second))),
+                Some(second) if audio(first) && !audio(second) => Some((Some(second), Some(first))),
+                Some(_) => None,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dash::AdaptiveStream;
+    use crate::streaminfo::label_fps;
+
+    fn candidate(
+        kind: Kind,
+        itag: u32,
+        mime_type: &str,
+        quality_label: Option<&str>,
+        bitrate: u32,
+    ) -> Candidate {
+        let height = quality_label.and_then(|label| label.split('p').next()?.parse().ok());
+        Candidate {
+            kind,
+            stream: AdaptiveStream {
+                itag,
+                mimeType: mime_type.to_owned(),
+                bitrate,
+                width: None,
+                height,
+                fps: quality_label.and_then(label_fps).map(|fps| fps as u32),
+                audioChannels: None,
+                audioSampleRate: None,
+                approxDurationMs: None,
+                contentLength: None,
+                initRange: None,
+                indexRange: None,
+            },
+        }
+    }
+
+    fn streams() -> Vec<Candidate> {
+        vec![
+            candidate(
+                Kind::Muxed,
+                18,
+                r#"video/mp4; codecs="avc1.42001E, mp4a.40.2""#,
+                Some("360p"),
+                500_000,
+            ),
+            candidate(
+                Kind::VideoOnly,
+                137,
+                r#"video/mp4; codecs="avc1.640028""#,
+                Some("1080p"),
+                4_000_000,
+            ),
+            candidate(
+                Kind::VideoOnly,
+                248,
+                r#"video/webm; codecs="vp9""#,
+                Some("1080p"),
+                2_500_000,
+            ),
+            candidate(
+                Kind::VideoOnly,
+                136,
+                r#"video/mp4; codecs="avc1.4d401f""#,
+                Some("720p60"),
+                2_000_000,
+            ),
+            candidate(
+                Kind::AudioOnly,
+                140,
+                r#"audio/mp4; codecs="mp4a.40.2""#,
+                None,
+                128_000,
+            ),
+            candidate(
+                Kind::AudioOnly,
+                251,
+                r#"audio/webm; codecs="opus""#,
+                None,
+                160_000,
+            ),
+        ]
+    }
+
+    /// The itags `expr` chooses as the video and the audio stream.
+    fn choose(expr: &str) -> Option<(Option<u32>, Option<u32>)> {
+        let candidates = streams();
+        let expr: FormatExpr = expr.parse().unwrap();
+        expr.choose(&candidates)
+            .map(|(video, audio)| (video.map(|c| c.stream.itag), audio.map(|c| c.stream.itag)))
+    }
+
+    fn parse_error(expr: &str) -> String {
+        match expr.parse::<FormatExpr>() {
+            Ok(_) => panic!("{:?} parsed", expr),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn plus_binds_tighter_than_slash() {
+        let expr: FormatExpr = "18/137+140".parse().unwrap();
+        assert_eq!(expr.alternatives.len(), 2);
+        assert!(expr.alternatives[0].1.is_none());
+        assert!(expr.alternatives[1].1.is_some());
+        assert_eq!(choose("18/137+140"), Some((Some(18), None)));
+        assert_eq!(choose("999/137+140"), Some((Some(137), Some(140))));
+        assert_eq!(choose("137+999/22"), None);
+    }
+
+    #[test]
+    fn optional_filters_pass_streams_without_the_field() {
+        assert_eq!(choose("ba[fps<=?30]"), Some((None, Some(251))));
+        assert_eq!(choose("ba[fps<=30]"), None);
+        assert_eq!(choose("ba[height<=?720]"), Some((None, Some(251))));
+    }
+
+    #[test]
+    fn frame_rates_come_from_quality_labels() {
+        assert_eq!(choose("bv[fps<=30]"), Some((Some(137), None)));
+        assert_eq!(choose("bv[fps>30]"), Some((Some(136), None)));
+        assert_eq!(choose("b[fps=30]"), Some((Some(18), None)));
+    }
+
+    #[test]
+    fn numeric_and_text_operators() {
+        assert_eq!(choose("bv[height<1080]"), Some((Some(136), None)));
+        assert_eq!(choose("bv[height=1080][tbr<3000]"), Some((Some(248), None)));
+        assert_eq!(choose("bv[vcodec^=vp9]"), Some((Some(248), None)));
+        assert_eq!(choose("ba[ext=m4a]"), Some((None, Some(140))));
+        assert_eq!(choose("ba[acodec*=opus]"), Some((None, Some(251))));
+        assert!(parse_error("bv[vcodec<5]").contains("cannot be compared"));
+        assert!(parse_error("bv[height^=7]").contains("cannot be compared"));
+    }
+
+    #[test]
+    fn merging_takes_video_then_audio() {
+        assert_eq!(choose("bv+ba"), Some((Some(137), Some(251))));
+        assert_eq!(choose("ba+bv"), Some((Some(137), Some(251))));
+        assert_eq!(choose("bv+bv"), None);
+        assert_eq!(choose("bv+bv/b"), Some((Some(18), None)));
+    }
+
+    #[test]
+    fn widened_formats_include_muxed_streams() {
+        assert_eq!(choose("wv"), Some((Some(136), None)));
+        assert_eq!(choose("wv*"), Some((Some(18), None)));
+        assert_eq!(choose("bv*[height<=360]+ba"), Some((Some(18), Some(251))));
+        assert_eq!(choose("ba*[height<=?240]"), Some((None, Some(251))));
+        assert_eq!(choose("w*"), Some((None, Some(140))));
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_problem() {
+        assert!(parse_error("bestvidoe").ends_with(r#"at position 0 of "bestvidoe""#));
+        assert!(parse_error("137/bx*").contains("at position 4"));
+        assert!(parse_error("bv/137+").contains("expected a format at position 7"));
+        assert!(parse_error("bv[heigth<=720]").contains("unknown field \"heigth\" at position 3"));
+        assert!(parse_error("bv[height<=abc]").contains("at position 11"));
+        assert!(parse_error("bv[height<=720").contains("expected ] at position 14"));
+        assert!(parse_error("bv ba").contains("unexpected input at position 3"));
+    }
+}
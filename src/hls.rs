@@ -0,0 +1,480 @@
+use crate::dash::{adaptive_streams, AdaptiveStream, ByteRange};
+use crate::errors::{error_response, parsing_error_response, ErrorCode};
+use crate::mp4::{read_u16, read_u32, read_u64};
+use crate::proxy::PROXY_POOL;
+use crate::serverrunner::{Context, Extractions};
+use crate::signing::{authorize, URL_SIGNER};
+use crate::vidproxy::{fetch, relay, resolve};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rusty_pipe::downloader_trait::Downloader;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use warp::http::header::{self, HeaderMap, HeaderValue};
+use warp::http::{Method, Response};
+use warp::hyper::Body;
+
+/// Hosts live manifests and their segments are fetched from.
+const LIVE_HOST: &str = "googlevideo.com";
+
+fn playlist_response(playlist: String) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")
+        .body(Body::from(playlist))
+        .unwrap_or_else(|e| error_response(ErrorCode::UpstreamError, e))
+}
+
+/// HLS can only carry the fragmented MP4 renditions, not WebM.
+fn mp4_renditions(streams: Vec<AdaptiveStream>) -> Vec<AdaptiveStream> {
+    streams
+        .into_iter()
+        .filter(|stream| stream.container_and_codecs().0.ends_with("/mp4"))
+        .collect()
+}
+
+/// A master playlist with every audio rendition in one group and a variant per video
+/// rendition, each pointing at its media playlist next to this one. Without video,
+/// every audio rendition is a variant of its own.
+fn master_playlist(video: &[AdaptiveStream], audio: &[AdaptiveStream]) -> String {
+    let mut m3u8 = String::from("#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-INDEPENDENT-SEGMENTS\n");
+    if video.is_empty() {
+        for stream in audio {
+            let _ = writeln!(
+                m3u8,
+                "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{}\"\n{}.m3u8",
+                stream.bitrate,
+                stream.container_and_codecs().1,
+                stream.itag
+            );
+        }
+        return m3u8;
+    }
+    let best_audio = audio.iter().max_by_key(|stream| stream.bitrate);
+    for stream in audio {
+        let _ = writeln!(
+            m3u8,
+            r#"#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="audio",NAME="{} kbps",DEFAULT={},AUTOSELECT=YES,CHANNELS="{}",URI="{}.m3u8""#,
+            stream.bitrate / 1000,
+            if Some(stream.itag) == best_audio.map(|best| best.itag) {
+                "YES"
+            } else {
+                "NO"
+            },
+            stream.audioChannels.unwrap_or(2),
+            stream.itag
+        );
+    }
+    for stream in video {
+        let mut codecs = stream.container_and_codecs().1.to_owned();
+        let mut bandwidth = stream.bitrate;
+        if let Some(best) = best_audio {
+            codecs = format!("{},{}", codecs, best.container_and_codecs().1);
+            bandwidth += best.bitrate;
+        }
+        let mut attributes = format!(r#"BANDWIDTH={},CODECS="{}""#, bandwidth, codecs);
+        if let (Some(width), Some(height)) = (stream.width, stream.height) {
+            let _ = write!(attributes, ",RESOLUTION={}x{}", width, height);
+        }
+        if let Some(fps) = stream.fps {
+            let _ = write!(attributes, ",FRAME-RATE={}", fps);
+        }
+        if best_audio.is_some() {
+            attributes.push_str(r#",AUDIO="audio""#);
+        }
+        let _ = writeln!(
+            m3u8,
+            "#EXT-X-STREAM-INF:{}\n{}.m3u8",
+            attributes, stream.itag
+        );
+    }
+    m3u8
+}
+
+struct Segment {
+    offset: u64,
+    size: u64,
+    duration: f64,
+}
+
+/// Reads the subsegments listed by the `sidx` box in `data`, which was read from byte
+/// `start` of the stream.
+fn parse_sidx(data: &[u8], start: u64) -> Option<Vec<Segment>> {
+    let mut pos = 0;
+    let size = loop {
+        let size = read_u32(data, pos)? as usize;
+        if data.get(pos + 4..pos + 8)? == b"sidx" {
+            break size;
+        }
+        if size < 8 {
+            return None;
+        }
+        pos += size;
+    };
+    let version = *data.get(pos + 8)?;
+    let timescale = read_u32(data, pos + 16)?;
+    let (first_offset, mut cursor) = if version == 0 {
+        (read_u32(data, pos + 24)?, pos + 28)
+    } else {
+        (read_u64(data, pos + 28)?, pos + 36)
+    };
+    let count = read_u16(data, cursor + 2)?;
+    cursor += 4;
+    if timescale == 0 {
+        return None;
+    }
+
+    let mut offset = start + (pos + size) as u64 + first_offset;
+    let mut segments = vec![];
+    for _ in 0..count {
+        let size = read_u32(data, cursor)? & 0x7fff_ffff;
+        let duration = read_u32(data, cursor + 4)?;
+        segments.push(Segment {
+            offset,
+            size,
+            duration: duration as f64 / timescale as f64,
+        });
+        offset += size;
+        cursor += 12;
+    }
+    Some(segments)
+}
+
+/// A VOD media playlist addressing byte ranges of the rendition's `/vid` URL.
+fn media_playlist(url: &str, init: (u64, u64), segments: &[Segment]) -> String {
+    let target = segments
+        .iter()
+        .map(|segment| segment.duration.ceil() as u64)
+        .max()
+        .unwrap_or(1);
+    let mut m3u8 = format!(
+        "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:{}\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-MEDIA-SEQUENCE:0\n",
+        target
+    );
+    let _ = writeln!(
+        m3u8,
+        r#"#EXT-X-MAP:URI="{}",BYTERANGE="{}@{}""#,
+        url,
+        init.1 - init.0 + 1,
+        init.0
+    );
+    for segment in segments {
+        let _ = writeln!(
+            m3u8,
+            "#EXTINF:{:.3},\n#EXT-X-BYTERANGE:{}@{}\n{}",
+            segment.duration, segment.size, segment.offset, url
+        );
+    }
+    m3u8.push_str("#EXT-X-ENDLIST\n");
+    m3u8
+}
+
+fn parse_range(range: &ByteRange) -> Option<(u64, u64)> {
+    Some((range.start.parse().ok()?, range.end.parse().ok()?))
+}
+
+/// Builds the media playlist of one rendition from its segment index, falling back to a
+/// single segment spanning the whole stream when the index cannot be read.
+async fn rendition_playlist<D>(
+    context: &Context,
+    video_id: &str,
+    stream: &AdaptiveStream,
+) -> Response<Body>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    let (init, index) = match (
+        stream.initRange.as_ref().and_then(parse_range),
+        stream.indexRange.as_ref().and_then(parse_range),
+    ) {
+        (Some(init), Some(index)) => (init, index),
+        _ => {
+            return error_response(
+                ErrorCode::NotFound,
+                format!("itag {} has no segment index", stream.itag),
+            )
+        }
+    };
+    let (resolved, _) = match resolve::<D>(video_id, stream.itag, false).await {
+        Ok(resolved) => resolved,
+        Err(resp) => return resp,
+    };
+
+    let mut headers = HeaderMap::new();
+    if let Ok(range) = HeaderValue::from_str(&format!("bytes={}-{}", index.0, index.1)) {
+        headers.insert(header::RANGE, range);
+    }
+    let proxy = PROXY_POOL.for_stream(video_id);
+    let sidx = match fetch(Method::GET, proxy, &resolved.url, &headers).await {
+        Ok(resp) if resp.status().is_success() => resp.bytes().await.ok(),
+        Ok(resp) => {
+            log::warn!(
+                "segment index of {} itag {}: {}",
+                video_id,
+                stream.itag,
+                resp.status()
+            );
+            None
+        }
+        Err(_) => None,
+    };
+    let segments = sidx
+        .and_then(|sidx| parse_sidx(&sidx, index.0))
+        .unwrap_or_else(|| {
+            let length = resolved
+                .content_length
+                .as_ref()
+                .and_then(|length| length.parse::<u64>().ok())
+                .unwrap_or(index.1 + 1);
+            vec![Segment {
+                offset: index.1 + 1,
+                size: length.saturating_sub(index.1 + 1),
+                duration: stream
+                    .approxDurationMs
+                    .as_ref()
+                    .and_then(|ms| ms.parse::<f64>().ok())
+                    .unwrap_or_default()
+                    / 1000.0,
+            }]
+        });
+    playlist_response(media_playlist(
+        &context.proxy_url(video_id, stream.itag),
+        init,
+        &segments,
+    ))
+}
+
+/// The upstream HLS manifest of a live video, read from its watch page.
+async fn live_manifest_url<D>(video_id: &str) -> Option<String>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    let page = D::download(&format!("https://www.youtube.com/watch?v={}", video_id))
+        .await
+        .ok()?;
+    let marker = "\"hlsManifestUrl\":\"";
+    let start = page.find(marker)? + marker.len();
+    let end = start + page[start..].find('"')?;
+    Some(page[start..end].replace("\\/", "/").replace("\\u0026", "&"))
+}
+
+/// The `/hls/{video_id}/live` URL relaying `upstream`, signed over the upstream URL so
+/// the route only fetches what a playlist of this server handed out.
+fn live_url(context: &Context, video_id: &str, upstream: &str) -> String {
+    let signature = URL_SIGNER.sign(video_id, upstream, context.client.as_deref());
+    format!(
+        "{}/hls/{}/live?url={}{}",
+        context.base_url,
+        video_id,
+        utf8_percent_encode(upstream, NON_ALPHANUMERIC),
+        signature.replacen('?', "&", 1)
+    )
+}
+
+/// Points every URI in an upstream playlist, including `URI="..."` attributes, at
+/// `/hls/{video_id}/live`.
+fn rewrite_live_playlist(
+    context: &Context,
+    video_id: &str,
+    base: &reqwest::Url,
+    playlist: &str,
+) -> String {
+    let absolute = |uri: &str| {
+        base.join(uri)
+            .map(|url| live_url(context, video_id, url.as_str()))
+            .unwrap_or_else(|_| uri.to_owned())
+    };
+    let mut rewritten = String::new();
+    for line in playlist.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            rewritten.push('\n');
+            continue;
+        }
+        if !line.starts_with('#') {
+            rewritten.push_str(&absolute(line));
+        } else if let Some(start) = line.find("URI=\"").map(|start| start + 5) {
+            match line[start..].find('"') {
+                Some(len) => {
+                    rewritten.push_str(&line[..start]);
+                    rewritten.push_str(&absolute(&line[start..start + len]));
+                    rewritten.push_str(&line[start + len..]);
+                }
+                None => rewritten.push_str(line),
+            }
+        } else {
+            rewritten.push_str(line);
+        }
+        rewritten.push('\n');
+    }
+    rewritten
+}
+
+/// Fetches a live playlist or segment, rewriting playlists so the player keeps coming back
+/// through the server.
+async fn live(
+    context: &Context,
+    video_id: &str,
+    upstream: &str,
+    headers: &HeaderMap,
+) -> Response<Body> {
+    let url = match reqwest::Url::parse(upstream) {
+        Ok(url) => url,
+        Err(_) => return error_response(ErrorCode::NotFound, "malformed live url"),
+    };
+    let allowed = url.host_str().map_or(false, |host| {
+        host == LIVE_HOST || host.ends_with(&format!(".{}", LIVE_HOST))
+    });
+    if !allowed {
+        return error_response(ErrorCode::NotFound, "not a live stream url");
+    }
+    let proxy = PROXY_POOL.for_stream(video_id);
+    let resp = match fetch(Method::GET, proxy, url.as_str(), headers).await {
+        Ok(resp) => resp,
+        Err(resp) => return resp,
+    };
+    let is_playlist = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.contains("mpegurl"));
+    if !is_playlist || !resp.status().is_success() {
+        return relay(resp, None);
+    }
+    let base = resp.url().clone();
+    match resp.text().await {
+        Ok(playlist) => {
+            playlist_response(rewrite_live_playlist(context, video_id, &base, &playlist))
+        }
+        Err(e) => error_response(ErrorCode::UpstreamError, e),
+    }
+}
+
+/// Serves `/hls/{video_id}/master.m3u8`, the per-rendition `/hls/{video_id}/{itag}.m3u8`
+/// playlists, and `/hls/{video_id}/live` for live videos.
+pub async fn hlshandle<D>(
+    video_id: String,
+    file: String,
+    query: HashMap<String, String>,
+    remote: Option<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, warp::Rejection>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    let context = Context::from_request(&headers, remote);
+    if file == "live" {
+        let upstream = match query.get("url") {
+            Some(upstream) => upstream,
+            None => return Ok(error_response(ErrorCode::NotFound, "missing live url")),
+        };
+        if let Err(resp) = authorize(&video_id, upstream, &query, remote, &headers) {
+            return Ok(resp);
+        }
+        return Ok(live(&context, &video_id, upstream, &headers).await);
+    }
+    let name = match file.strip_suffix(".m3u8") {
+        Some(name) => name,
+        None => return Err(warp::reject::not_found()),
+    };
+
+    let videx = match Extractions::<D>::get().video(&video_id).await {
+        Ok(videx) => videx,
+        Err(e) => {
+            log::warn!("cant extract {} for hls: {}", video_id, e);
+            return Ok(parsing_error_response(e));
+        }
+    };
+    let video = mp4_renditions(adaptive_streams(
+        videx.get_video_only_stream().unwrap_or_default(),
+    ));
+    let audio = mp4_renditions(adaptive_streams(
+        videx.get_audio_streams().unwrap_or_default(),
+    ));
+
+    if name == "master" {
+        if !video.is_empty() || !audio.is_empty() {
+            return Ok(playlist_response(master_playlist(&video, &audio)));
+        }
+        return Ok(match live_manifest_url::<D>(&video_id).await {
+            Some(upstream) => live(&context, &video_id, &upstream, &HeaderMap::new()).await,
+            None => error_response(
+                ErrorCode::NotFound,
+                format!("no hls renditions for {}", video_id),
+            ),
+        });
+    }
+    let itag: u32 = match name.parse() {
+        Ok(itag) => itag,
+        Err(_) => return Err(warp::reject::not_found()),
+    };
+    Ok(
+        match video
+            .iter()
+            .chain(audio.iter())
+            .find(|stream| stream.itag == itag)
+        {
+            Some(stream) => rendition_playlist::<D>(&context, &video_id, stream).await,
+            None => error_response(
+                ErrorCode::NotFound,
+                format!("no hls rendition of itag {} for {}", itag, video_id),
+            ),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendition(itag: u32, mime_type: &str, bitrate: u32, height: Option<u32>) -> AdaptiveStream {
+        AdaptiveStream {
+            itag,
+            mimeType: mime_type.to_owned(),
+            bitrate,
+            width: height.map(|height| height * 16 / 9),
+            height,
+            fps: None,
+            audioChannels: None,
+            audioSampleRate: None,
+            approxDurationMs: None,
+            contentLength: None,
+            initRange: None,
+            indexRange: None,
+        }
+    }
+
+    #[test]
+    fn audio_only_masters_list_every_rendition_as_a_variant() {
+        let audio = vec![
+            rendition(139, r#"audio/mp4; codecs="mp4a.40.5""#, 48_000, None),
+            rendition(140, r#"audio/mp4; codecs="mp4a.40.2""#, 128_000, None),
+        ];
+        let m3u8 = master_playlist(&[], &audio);
+        assert!(!m3u8.contains("#EXT-X-MEDIA"));
+        assert!(m3u8.contains("#EXT-X-STREAM-INF:BANDWIDTH=48000,CODECS=\"mp4a.40.5\"\n139.m3u8\n"));
+        assert!(
+            m3u8.contains("#EXT-X-STREAM-INF:BANDWIDTH=128000,CODECS=\"mp4a.40.2\"\n140.m3u8\n")
+        );
+    }
+
+    #[test]
+    fn video_variants_carry_the_best_audio() {
+        let video = vec![rendition(
+            137,
+            r#"video/mp4; codecs="avc1.640028""#,
+            4_000_000,
+            Some(1080),
+        )];
+        let audio = vec![rendition(
+            140,
+            r#"audio/mp4; codecs="mp4a.40.2""#,
+            128_000,
+            None,
+        )];
+        let m3u8 = master_playlist(&video, &audio);
+        assert!(m3u8.contains(r#"GROUP-ID="audio",NAME="128 kbps",DEFAULT=YES"#));
+        assert!(m3u8.contains(
+            r#"#EXT-X-STREAM-INF:BANDWIDTH=4128000,CODECS="avc1.640028,mp4a.40.2",RESOLUTION=1920x1080,AUDIO="audio""#
+        ));
+    }
+}
@@ -0,0 +1,193 @@
+use crate::proxy::PROXY_POOL;
+use crate::ratelimit::{
+    is_blocked_page, rate_limited, DownloadFailure, CIRCUIT_BREAKER, RATE_LIMITER,
+};
+use lazy_static::lazy_static;
+use reqwest::header::HeaderMap;
+use reqwest::redirect::Policy;
+use reqwest::{Client, ClientBuilder, StatusCode};
+use rusty_pipe::youtube_extractor::error::ParsingError;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Tunables for the upstream client, read once from the environment.
+pub struct ClientConfig {
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout: Duration,
+    pub max_retries: u32,
+    pub retry_base_delay: Duration,
+}
+
+impl ClientConfig {
+    pub fn from_env() -> Self {
+        ClientConfig {
+            connect_timeout: Duration::from_millis(env_or("UPSTREAM_CONNECT_TIMEOUT_MS", 5_000)),
+            read_timeout: Duration::from_millis(env_or("UPSTREAM_READ_TIMEOUT_MS", 20_000)),
+            pool_max_idle_per_host: env_or("UPSTREAM_POOL_MAX_IDLE", 32),
+            pool_idle_timeout: Duration::from_secs(env_or("UPSTREAM_POOL_IDLE_TIMEOUT_SECS", 90)),
+            max_retries: env_or("UPSTREAM_MAX_RETRIES", 2),
+            retry_base_delay: Duration::from_millis(env_or("UPSTREAM_RETRY_BASE_MS", 200)),
+        }
+    }
+
+    fn builder(&self, proxy: Option<&str>) -> reqwest::Result<ClientBuilder> {
+        let mut builder = Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout);
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        Ok(builder)
+    }
+
+    /// Builds a pooled client for page downloads, routed through `proxy` (`http://`,
+    /// `https://` or `socks5://`) when one is given.
+    pub fn build_client(&self, proxy: Option<&str>) -> reqwest::Result<Client> {
+        self.builder(proxy)?.timeout(self.read_timeout).build()
+    }
+
+    /// Builds a pooled client for media. It has no overall timeout, as bodies stream for
+    /// as long as playback lasts, and leaves redirects to the caller.
+    pub fn build_media_client(&self, proxy: Option<&str>) -> reqwest::Result<Client> {
+        self.builder(proxy)?.redirect(Policy::none()).build()
+    }
+
+    /// Delay before retrying after `attempt` failed, doubling each time.
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.retry_base_delay * 2u32.pow(attempt.saturating_sub(1).min(6))
+    }
+}
+
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+lazy_static! {
+    pub static ref CLIENT_CONFIG: ClientConfig = ClientConfig::from_env();
+}
+
+fn is_transient(err: &reqwest::Error) -> bool {
+    err.is_timeout() || err.is_request() || err.is_body()
+}
+
+/// GETs `url` through the proxy pool, retrying transient failures and 5xx answers with
+/// exponential backoff. A proxy answering 429 or 403 is rested and the next attempt
+/// goes out through another one; once no route is left the circuit breaker opens.
+pub async fn fetch_text(url: &str, headers: HeaderMap) -> Result<String, ParsingError> {
+    let config = &*CLIENT_CONFIG;
+    let attempts = config.max_retries + 1;
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_owned))
+        .unwrap_or_default();
+    let mut attempt = 1;
+    loop {
+        CIRCUIT_BREAKER.check()?;
+        RATE_LIMITER.acquire(&host).await;
+        let proxy = PROXY_POOL.pick();
+        let client = &PROXY_POOL.get(proxy).client;
+        let (failure, cause) = match client.get(url).headers(headers.clone()).send().await {
+            Ok(resp) if resp.status() == StatusCode::TOO_MANY_REQUESTS => {
+                let cause = format!("upstream answered {} for {}", resp.status(), url);
+                throttle(proxy, &cause)?;
+                (Some(status_failure(&resp)), cause)
+            }
+            Ok(resp) if resp.status() == StatusCode::NOT_FOUND => {
+                let cause = format!("upstream answered {}", resp.status());
+                let failure = Some(status_failure(&resp));
+                return Err(download_error(url, attempt, attempts, failure, cause));
+            }
+            Ok(resp) if resp.status().is_server_error() => (
+                Some(status_failure(&resp)),
+                format!("upstream answered {}", resp.status()),
+            ),
+            Ok(resp) if PROXY_POOL.len() > 1 && resp.status() == StatusCode::FORBIDDEN => {
+                PROXY_POOL.report_failure(proxy);
+                let cause = format!(
+                    "upstream answered {} via {}",
+                    resp.status(),
+                    PROXY_POOL.get(proxy).url
+                );
+                (Some(status_failure(&resp)), cause)
+            }
+            Ok(resp) => {
+                let final_url = resp.url().clone();
+                match resp.text().await {
+                    Ok(body) if is_blocked_page(&final_url, &body) => {
+                        let cause =
+                            format!("upstream served a consent or captcha page at {}", final_url);
+                        throttle(proxy, &cause)?;
+                        (Some(DownloadFailure::RateLimited), cause)
+                    }
+                    Ok(body) => {
+                        PROXY_POOL.report_success(proxy);
+                        PROXY_POOL.report_page(proxy, url);
+                        return Ok(body);
+                    }
+                    Err(er) if is_transient(&er) => (None, er.to_string()),
+                    Err(er) => return Err(download_error(url, attempt, attempts, None, er)),
+                }
+            }
+            Err(er) if is_transient(&er) => {
+                PROXY_POOL.report_failure(proxy);
+                (None, er.to_string())
+            }
+            Err(er) => return Err(download_error(url, attempt, attempts, None, er)),
+        };
+        if attempt >= attempts {
+            return Err(download_error(url, attempt, attempts, failure, cause));
+        }
+        log::warn!(
+            "attempt {}/{} for {} failed: {}, retrying",
+            attempt,
+            attempts,
+            url,
+            cause
+        );
+        tokio::time::delay_for(config.backoff(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Rests `proxy` after a throttled answer. Once no healthy route is left the throttling
+/// counts towards the breaker, and surfaces as a rate-limit error when it trips it.
+fn throttle(proxy: usize, cause: &str) -> Result<(), ParsingError> {
+    PROXY_POOL.report_failure(proxy);
+    if PROXY_POOL.len() > 1 && PROXY_POOL.any_healthy() {
+        return Ok(());
+    }
+    if CIRCUIT_BREAKER.throttled() {
+        return Err(rate_limited(cause));
+    }
+    Ok(())
+}
+
+fn status_failure(resp: &reqwest::Response) -> DownloadFailure {
+    DownloadFailure::Status(resp.status().as_u16())
+}
+
+fn download_error(
+    url: &str,
+    attempt: u32,
+    attempts: u32,
+    failure: Option<DownloadFailure>,
+    cause: impl ToString,
+) -> ParsingError {
+    let cause = format!(
+        "attempt {}/{} for {} failed: {}",
+        attempt,
+        attempts,
+        url,
+        cause.to_string()
+    );
+    match failure {
+        Some(failure) => failure.error(cause),
+        None => ParsingError::DownloadError { cause },
+    }
+}
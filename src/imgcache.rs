@@ -0,0 +1,129 @@
+use crate::httpclient::env_or;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Index of the images stored under `IMG_CACHE_DIR`, evicting the least recently served
+/// files once they take more than `IMG_CACHE_MAX_BYTES`. Files left by an earlier run
+/// are picked up oldest first.
+pub struct ImageCache {
+    dir: PathBuf,
+    entries: LruCache<String, u64>,
+    bytes: u64,
+    max_bytes: u64,
+}
+
+impl ImageCache {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        let mut cache = ImageCache {
+            dir,
+            entries: LruCache::unbounded(),
+            bytes: 0,
+            max_bytes,
+        };
+        let mut existing: Vec<_> = std::fs::read_dir(&cache.dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                let name = entry.file_name().into_string().ok()?;
+                if !metadata.is_file() || name.ends_with(".tmp") {
+                    return None;
+                }
+                Some((metadata.modified().ok()?, name, metadata.len()))
+            })
+            .collect();
+        existing.sort();
+        for (_, key, len) in existing {
+            cache.bytes += len;
+            cache.entries.put(key, len);
+        }
+        let evicted = cache.evict();
+        for path in evicted {
+            let _ = std::fs::remove_file(path);
+        }
+        cache
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    /// Drops entries until the cache fits, returning the files to delete.
+    fn evict(&mut self) -> Vec<PathBuf> {
+        let mut evicted = vec![];
+        while self.bytes > self.max_bytes {
+            match self.entries.pop_lru() {
+                Some((key, len)) => {
+                    self.bytes -= len;
+                    evicted.push(self.path(&key));
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(len) = self.entries.pop(key) {
+            self.bytes -= len;
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref IMAGE_CACHE: Mutex<ImageCache> = Mutex::new(ImageCache::new(
+        PathBuf::from(std::env::var("IMG_CACHE_DIR").unwrap_or("img-cache".to_owned())),
+        env_or("IMG_CACHE_MAX_BYTES", 512 * 1024 * 1024),
+    ));
+}
+
+/// Reads a cached image, forgetting it if the file has gone missing.
+pub async fn get(key: &str) -> Option<Vec<u8>> {
+    let path = {
+        let mut cache = IMAGE_CACHE.lock().unwrap();
+        cache.entries.get(key)?;
+        cache.path(key)
+    };
+    match tokio::fs::read(&path).await {
+        Ok(bytes) => Some(bytes),
+        Err(e) => {
+            log::warn!("cached image {} unreadable: {}", path.display(), e);
+            IMAGE_CACHE.lock().unwrap().remove(key);
+            None
+        }
+    }
+}
+
+/// Writes an image to the cache, deleting the least recently served ones to make room.
+pub async fn put(key: &str, bytes: &[u8]) {
+    let (dir, path) = {
+        let cache = IMAGE_CACHE.lock().unwrap();
+        if bytes.len() as u64 > cache.max_bytes {
+            return;
+        }
+        (cache.dir.clone(), cache.path(key))
+    };
+    let tmp = path.with_extension("tmp");
+    let written = async {
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(&tmp, bytes).await?;
+        tokio::fs::rename(&tmp, &path).await
+    };
+    if let Err(e) = written.await {
+        log::warn!("cant cache image {}: {}", path.display(), e);
+        return;
+    }
+    let evicted = {
+        let mut cache = IMAGE_CACHE.lock().unwrap();
+        cache.remove(key);
+        cache.bytes += bytes.len() as u64;
+        cache.entries.put(key.to_owned(), bytes.len() as u64);
+        cache.evict()
+    };
+    for path in evicted {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+}
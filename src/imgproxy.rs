@@ -0,0 +1,313 @@
+use crate::errors::{error_response, ErrorCode};
+use crate::httpclient::env_or;
+use crate::imgcache;
+use crate::proxy::PROXY_POOL;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+use warp::http::header;
+use warp::http::{Response, StatusCode};
+use warp::hyper::Body;
+use warp::path::Tail;
+
+/// Image CDNs `/img` fetches from; anything else would make it an open proxy.
+const IMAGE_HOSTS: [&str; 3] = ["ytimg.com", "ggpht.com", "googleusercontent.com"];
+
+/// Query parameters `/img` consumes itself instead of passing them upstream.
+const VARIANT_PARAMS: [&str; 3] = ["w", "format", "q"];
+
+pub fn allowed_host(host: &str) -> bool {
+    IMAGE_HOSTS
+        .iter()
+        .any(|allowed| host == *allowed || host.ends_with(&format!(".{}", allowed)))
+}
+
+/// `/img` URL serving the upstream image `url`, or `None` when its host is not proxied.
+pub fn proxy_url(base_url: &str, url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let host = url.host_str().filter(|host| allowed_host(host))?;
+    let mut proxied = format!("{}/img/{}{}", base_url, host, url.path());
+    if let Some(query) = url.query() {
+        proxied.push('?');
+        proxied.push_str(query);
+    }
+    Some(proxied)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Jpeg,
+    Png,
+    WebP,
+}
+
+impl Format {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(Format::Jpeg),
+            "png" => Some(Format::Png),
+            "webp" => Some(Format::WebP),
+            _ => None,
+        }
+    }
+}
+
+/// How a client asked for an image to be re-encoded: `w` caps the width, `format` picks
+/// the encoding and `q` the JPEG quality.
+#[derive(Debug, Default)]
+struct Variant {
+    width: Option<u32>,
+    format: Option<Format>,
+    quality: Option<u8>,
+}
+
+impl Variant {
+    fn is_original(&self) -> bool {
+        self.width.is_none() && self.format.is_none()
+    }
+}
+
+/// Splits the raw query into the upstream part, kept byte for byte since thumbnail
+/// URLs carry signatures, and the requested variant.
+fn split_query(query: &str) -> Result<(String, Variant), String> {
+    let mut upstream = vec![];
+    let mut variant = Variant::default();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let key = parts.next().unwrap_or_default();
+        let value = parts.next().unwrap_or_default();
+        if !VARIANT_PARAMS.contains(&key) {
+            upstream.push(pair);
+            continue;
+        }
+        match key {
+            "w" => {
+                let width: u32 = value.parse().map_err(|_| "w must be a width in pixels")?;
+                if width == 0 {
+                    return Err("w must be a width in pixels".to_owned());
+                }
+                variant.width = Some(width.min(env_or("IMG_MAX_WIDTH", 2560)));
+            }
+            "format" => {
+                variant.format =
+                    Some(Format::parse(value).ok_or("format must be jpeg, png or webp")?);
+            }
+            _ => {
+                let quality: u8 = value.parse().map_err(|_| "q must be between 1 and 100")?;
+                if quality == 0 || quality > 100 {
+                    return Err("q must be between 1 and 100".to_owned());
+                }
+                variant.quality = Some(quality);
+            }
+        }
+    }
+    Ok((upstream.join("&"), variant))
+}
+
+fn content_type(bytes: &[u8]) -> &'static str {
+    match image::guess_format(bytes) {
+        Ok(image::ImageFormat::Jpeg) => "image/jpeg",
+        Ok(image::ImageFormat::Png) => "image/png",
+        Ok(image::ImageFormat::WebP) => "image/webp",
+        Ok(image::ImageFormat::Gif) => "image/gif",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Resizes and re-encodes an upstream image. Images are only ever scaled down, and keep
+/// their encoding unless another one was asked for.
+/// Refuses images over `IMG_MAX_PIXELS` from the size in their header, so a small file
+/// declaring huge dimensions is never decoded.
+fn check_dimensions(bytes: &[u8], format: ImageFormat) -> Result<(), String> {
+    let max_pixels: u64 = env_or("IMG_MAX_PIXELS", 40_000_000);
+    let mut reader = image::io::Reader::new(Cursor::new(bytes));
+    reader.set_format(format);
+    let (width, height) = reader.into_dimensions().map_err(|e| e.to_string())?;
+    if width as u64 * height as u64 > max_pixels {
+        return Err(format!(
+            "{}x{} image exceeds {} pixels",
+            width, height, max_pixels
+        ));
+    }
+    Ok(())
+}
+
+fn transform(bytes: &[u8], variant: &Variant) -> Result<Vec<u8>, String> {
+    let source = image::guess_format(bytes).map_err(|e| e.to_string())?;
+    check_dimensions(bytes, source)?;
+    let mut img = image::load_from_memory_with_format(bytes, source).map_err(|e| e.to_string())?;
+    if let Some(width) = variant.width {
+        if width < img.width() {
+            let height = (img.height() as u64 * width as u64 / img.width() as u64).max(1);
+            img = img.resize_exact(width, height as u32, FilterType::Triangle);
+        }
+    }
+    let format = variant.format.unwrap_or(match source {
+        ImageFormat::Png => Format::Png,
+        ImageFormat::WebP => Format::WebP,
+        _ => Format::Jpeg,
+    });
+    let mut out = vec![];
+    match format {
+        Format::Jpeg => {
+            let quality = variant
+                .quality
+                .unwrap_or_else(|| env_or("IMG_JPEG_QUALITY", 80));
+            img.write_to(&mut out, ImageOutputFormat::Jpeg(quality))
+                .map_err(|e| e.to_string())?
+        }
+        Format::Png => img
+            .write_to(&mut out, ImageOutputFormat::Png)
+            .map_err(|e| e.to_string())?,
+        Format::WebP => encode_webp(&img, &mut out)?,
+    }
+    Ok(out)
+}
+
+/// Lossless WebP, the only kind there is a pure Rust encoder for.
+fn encode_webp(img: &DynamicImage, out: &mut Vec<u8>) -> Result<(), String> {
+    let rgba = img.to_rgba();
+    image_webp::WebPEncoder::new(out)
+        .encode(
+            rgba.as_raw(),
+            rgba.width(),
+            rgba.height(),
+            image_webp::ColorType::Rgba8,
+        )
+        .map_err(|e| e.to_string())
+}
+
+fn image_response(bytes: Vec<u8>, etag: &str) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type(&bytes))
+        .header(
+            header::CACHE_CONTROL,
+            format!(
+                "public, max-age={}, immutable",
+                env_or("IMG_MAX_AGE_SECS", 30 * 86400)
+            ),
+        )
+        .header(header::ETAG, etag)
+        .body(Body::from(bytes))
+        .unwrap_or_else(|e| error_response(ErrorCode::UpstreamError, e))
+}
+
+/// Downloads an image through the proxy pool, refusing anything over `IMG_MAX_UPSTREAM_BYTES`,
+/// along with the `Content-Type` the image host sent.
+async fn fetch_image(url: &str) -> Result<(Vec<u8>, Option<String>), Response<Body>> {
+    let max_bytes: usize = env_or("IMG_MAX_UPSTREAM_BYTES", 8 * 1024 * 1024);
+    let proxy = PROXY_POOL.pick();
+    let resp = PROXY_POOL
+        .get(proxy)
+        .client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| {
+            log::warn!("image fetch failed {}: {:#}", url, e);
+            PROXY_POOL.report_failure(proxy);
+            error_response(ErrorCode::UpstreamError, e)
+        })?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Err(error_response(ErrorCode::NotFound, "no such image"));
+    }
+    if !resp.status().is_success() {
+        return Err(error_response(
+            ErrorCode::UpstreamError,
+            format!("image host answered {}", resp.status()),
+        ));
+    }
+    if resp.content_length().unwrap_or(0) > max_bytes as u64 {
+        return Err(error_response(ErrorCode::UpstreamError, "image too large"));
+    }
+    let mime_type = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let bytes = resp
+        .bytes()
+        .await
+        .map_err(|e| error_response(ErrorCode::UpstreamError, e))?;
+    if bytes.len() > max_bytes {
+        return Err(error_response(ErrorCode::UpstreamError, "image too large"));
+    }
+    Ok((bytes.to_vec(), mime_type))
+}
+
+pub async fn imgproxyhandle(
+    host: String,
+    path: Tail,
+    query: String,
+    if_none_match: Option<String>,
+) -> Result<Response<Body>, warp::Rejection> {
+    if !allowed_host(&host) {
+        return Ok(error_response(
+            ErrorCode::NotFound,
+            format!("{} is not an image host", host),
+        ));
+    }
+    let (upstream_query, variant) = match split_query(&query) {
+        Ok(split) => split,
+        Err(e) => return Ok(error_response(ErrorCode::BadRequest, e)),
+    };
+    let mut url = format!("https://{}/{}", host, path.as_str());
+    if !upstream_query.is_empty() {
+        url.push('?');
+        url.push_str(&upstream_query);
+    }
+
+    let key = format!(
+        "{:x}",
+        Sha256::digest(format!("{}\n{:?}", url, variant).as_bytes())
+    );
+    let etag = format!("\"{}\"", key);
+    // The ETag only names the variant, so it is only vouched for while the image that
+    // was served under it is still cached; a failed transform is never cached.
+    if let Some(bytes) = imgcache::get(&key).await {
+        if if_none_match.map_or(false, |tags| tags.split(',').any(|tag| tag.trim() == etag)) {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, etag.as_str())
+                .body(Body::empty())
+                .unwrap_or_else(|e| error_response(ErrorCode::UpstreamError, e)));
+        }
+        return Ok(image_response(bytes, &etag));
+    }
+
+    let (original, mime_type) = match fetch_image(&url).await {
+        Ok(fetched) => fetched,
+        Err(resp) => return Ok(resp),
+    };
+    if variant.is_original() {
+        imgcache::put(&key, &original).await;
+        return Ok(image_response(original, &etag));
+    }
+    let transformed = tokio::task::spawn_blocking(move || {
+        transform(&original, &variant).map_err(|e| (e, original))
+    })
+    .await;
+    match transformed {
+        Ok(Ok(bytes)) => {
+            imgcache::put(&key, &bytes).await;
+            Ok(image_response(bytes, &etag))
+        }
+        Ok(Err((e, original))) => {
+            // Not cached, so the variant is tried again on the next request.
+            log::warn!("cant transform image {}: {}", url, e);
+            let mime_type = mime_type.unwrap_or_else(|| content_type(&original).to_owned());
+            Ok(Response::builder()
+                .header(header::CONTENT_TYPE, mime_type)
+                .body(Body::from(original))
+                .unwrap_or_else(|e| error_response(ErrorCode::UpstreamError, e)))
+        }
+        Err(e) => {
+            log::warn!("image transform panicked {}: {}", url, e);
+            Ok(error_response(
+                ErrorCode::ParseError,
+                "cant transform image",
+            ))
+        }
+    }
+}
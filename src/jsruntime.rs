@@ -0,0 +1,340 @@
+use crate::httpclient::env_or;
+use crate::scalars::Long;
+use lazy_static::lazy_static;
+use libquickjs_sys as q;
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Job {
+    /// Definitions `script` relies on, evaluated once per runtime rather than per job.
+    prelude: Option<String>,
+    script: String,
+    reply: Sender<Result<String, String>>,
+}
+
+#[derive(Default)]
+struct JsMetrics {
+    evaluations: u64,
+    errors: u64,
+    timeouts: u64,
+    total_time: Duration,
+    max_time: Duration,
+}
+
+/// A QuickJS runtime with a memory cap, a context for plain scripts and one context
+/// per loaded prelude, so preludes defining the same globals never clash. Its interrupt
+/// handler stops a script once the deadline of the current evaluation passes, so a
+/// runaway script frees its thread instead of spinning on it.
+struct Runtime {
+    runtime: *mut q::JSRuntime,
+    context: *mut q::JSContext,
+    /// Contexts that have evaluated a prelude, keyed by the prelude's digest.
+    preludes: LruCache<String, *mut q::JSContext>,
+    deadline: Box<Cell<Option<Instant>>>,
+}
+
+unsafe extern "C" fn interrupt(_: *mut q::JSRuntime, deadline: *mut c_void) -> c_int {
+    let deadline = &*(deadline as *const Cell<Option<Instant>>);
+    match deadline.get() {
+        Some(deadline) if Instant::now() >= deadline => 1,
+        _ => 0,
+    }
+}
+
+impl Runtime {
+    fn new(memory_limit: usize, preludes: usize) -> Option<Self> {
+        let deadline = Box::new(Cell::new(None));
+        unsafe {
+            let runtime = q::JS_NewRuntime();
+            if runtime.is_null() {
+                return None;
+            }
+            q::JS_SetMemoryLimit(runtime, memory_limit as q::size_t);
+            let opaque = &*deadline as *const Cell<Option<Instant>> as *mut c_void;
+            q::JS_SetInterruptHandler(runtime, Some(interrupt), opaque);
+            let context = q::JS_NewContext(runtime);
+            if context.is_null() {
+                q::JS_FreeRuntime(runtime);
+                return None;
+            }
+            Some(Runtime {
+                runtime,
+                context,
+                preludes: LruCache::new(preludes.max(1)),
+                deadline,
+            })
+        }
+    }
+
+    /// Evaluates `script` in the context that has evaluated `prelude`, loading the
+    /// prelude into a new context first if needed. The least recently used context is
+    /// freed when there are more preludes than the runtime keeps.
+    fn eval_with(
+        &mut self,
+        prelude: Option<&str>,
+        script: &str,
+        timeout: Duration,
+    ) -> Result<String, String> {
+        let prelude = match prelude {
+            Some(prelude) => prelude,
+            None => return self.eval(self.context, script, timeout),
+        };
+        let key = format!("{:x}", Sha256::digest(prelude.as_bytes()));
+        if let Some(context) = self.preludes.get(&key).copied() {
+            return self.eval(context, script, timeout);
+        }
+        let context = unsafe { q::JS_NewContext(self.runtime) };
+        if context.is_null() {
+            return Err("Cant create js context".to_owned());
+        }
+        if let Err(e) = self.eval(context, prelude, timeout) {
+            unsafe { q::JS_FreeContext(context) };
+            return Err(e);
+        }
+        if self.preludes.len() == self.preludes.cap() {
+            if let Some((_, evicted)) = self.preludes.pop_lru() {
+                unsafe { q::JS_FreeContext(evicted) };
+            }
+        }
+        self.preludes.put(key, context);
+        self.eval(context, script, timeout)
+    }
+
+    /// Evaluates `script` in `context`, interrupting it once `timeout` has passed.
+    /// Strings, numbers and booleans come back as text, anything else as an empty string.
+    fn eval(
+        &self,
+        context: *mut q::JSContext,
+        script: &str,
+        timeout: Duration,
+    ) -> Result<String, String> {
+        let source = CString::new(script).map_err(|e| e.to_string())?;
+        let deadline = Instant::now() + timeout;
+        self.deadline.set(Some(deadline));
+        let result = unsafe {
+            let value = q::JS_Eval(
+                context,
+                source.as_ptr(),
+                script.len() as q::size_t,
+                b"player.js\0".as_ptr() as *const c_char,
+                q::JS_EVAL_TYPE_GLOBAL as c_int,
+            );
+            let result = if q::JS_IsException(value) {
+                let exception = q::JS_GetException(context);
+                let message = Self::text(context, exception);
+                q::JS_FreeValue(context, exception);
+                Err(message.unwrap_or_else(|| "js exception".to_owned()))
+            } else if q::JS_IsString(value) || q::JS_IsNumber(value) || q::JS_IsBool(value) {
+                Self::text(context, value).ok_or_else(|| "cant read js result".to_owned())
+            } else {
+                Ok(String::new())
+            };
+            q::JS_FreeValue(context, value);
+            result
+        };
+        self.deadline.set(None);
+        match result {
+            Err(_) if Instant::now() >= deadline => {
+                Err(format!("js evaluation timed out after {:?}", timeout))
+            }
+            result => result,
+        }
+    }
+
+    /// `value` converted to a string the way JavaScript's `String()` would.
+    unsafe fn text(context: *mut q::JSContext, value: q::JSValue) -> Option<String> {
+        let mut len: q::size_t = 0;
+        let ptr = q::JS_ToCStringLen2(context, &mut len, value, 0);
+        if ptr.is_null() {
+            return None;
+        }
+        let bytes = std::slice::from_raw_parts(ptr as *const u8, len as usize);
+        let text = String::from_utf8_lossy(bytes).into_owned();
+        q::JS_FreeCString(context, ptr);
+        Some(text)
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        unsafe {
+            while let Some((_, context)) = self.preludes.pop_lru() {
+                q::JS_FreeContext(context);
+            }
+            q::JS_FreeContext(self.context);
+            q::JS_FreeRuntime(self.runtime);
+        }
+    }
+}
+
+/// A fixed set of `JS_RUNTIMES` threads each owning a memory-capped QuickJS runtime,
+/// fed from one queue. Runtimes are reused across evaluations and recycled after a
+/// failure or every `JS_RECYCLE_AFTER` evaluations. An evaluation is interrupted after
+/// `JS_TIMEOUT_MS`, so the threads are never replaced and their number stays fixed.
+pub struct JsPool {
+    jobs: Mutex<Sender<Job>>,
+    queue: Arc<Mutex<Receiver<Job>>>,
+    timeout: Duration,
+    queue_timeout: Duration,
+    memory_limit: usize,
+    preludes: usize,
+    recycle_after: u32,
+    metrics: Mutex<JsMetrics>,
+}
+
+impl JsPool {
+    pub fn from_env() -> Self {
+        let (jobs, queue) = mpsc::channel();
+        let pool = JsPool {
+            jobs: Mutex::new(jobs),
+            queue: Arc::new(Mutex::new(queue)),
+            timeout: Duration::from_millis(env_or("JS_TIMEOUT_MS", 2_000)),
+            queue_timeout: Duration::from_millis(env_or("JS_QUEUE_TIMEOUT_MS", 10_000)),
+            memory_limit: env_or("JS_MEMORY_LIMIT_BYTES", 64 * 1024 * 1024),
+            preludes: env_or("JS_PRELUDES", 4),
+            recycle_after: env_or("JS_RECYCLE_AFTER", 256),
+            metrics: Mutex::new(JsMetrics::default()),
+        };
+        for _ in 0..env_or("JS_RUNTIMES", 2usize).max(1) {
+            pool.spawn_worker();
+        }
+        pool
+    }
+
+    fn spawn_worker(&self) {
+        let queue = self.queue.clone();
+        let timeout = self.timeout;
+        let memory_limit = self.memory_limit;
+        let preludes = self.preludes;
+        let recycle_after = self.recycle_after;
+        let spawned = thread::Builder::new()
+            .name("js-runtime".to_owned())
+            .spawn(move || {
+                let mut runtime: Option<Runtime> = None;
+                let mut evaluations = 0;
+                loop {
+                    let job = match queue.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    if runtime.is_none() || evaluations >= recycle_after {
+                        runtime = Runtime::new(memory_limit, preludes);
+                        evaluations = 0;
+                    }
+                    let result = match &mut runtime {
+                        Some(runtime) => {
+                            runtime.eval_with(job.prelude.as_deref(), &job.script, timeout)
+                        }
+                        None => Err("Cant create js context".to_owned()),
+                    };
+                    evaluations += 1;
+                    if result.is_err() {
+                        runtime = None;
+                    }
+                    let _ = job.reply.send(result);
+                }
+            });
+        if let Err(e) = spawned {
+            log::error!("cant spawn js runtime thread: {}", e);
+        }
+    }
+
+    /// Evaluates `script` on a pooled runtime, blocking the calling thread until it is
+    /// done. Video extractions run on the blocking pool for this reason.
+    pub fn eval(&self, script: &str) -> Result<String, String> {
+        self.run(None, script)
+    }
+
+    /// Evaluates `script` on a runtime that has evaluated `prelude`. Each runtime keeps
+    /// up to `JS_PRELUDES` preludes loaded, so calls sharing one only pay for it once,
+    /// even while several player versions are in use.
+    pub fn eval_with(&self, prelude: &str, script: &str) -> Result<String, String> {
+        self.run(Some(prelude), script)
+    }
+
+    fn run(&self, prelude: Option<&str>, script: &str) -> Result<String, String> {
+        let (reply, result) = mpsc::channel();
+        let started = Instant::now();
+        self.jobs
+            .lock()
+            .unwrap()
+            .send(Job {
+                prelude: prelude.map(str::to_owned),
+                script: script.to_owned(),
+                reply,
+            })
+            .map_err(|e| e.to_string())?;
+        // A job waits for a free runtime and then runs for at most `timeout`.
+        let wait = self.queue_timeout + self.timeout;
+        let result = match result.recv_timeout(wait) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                Err(format!("js runtimes busy for {:?}", self.queue_timeout))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err("js runtime died".to_owned()),
+        };
+        self.record(started.elapsed(), &result);
+        result
+    }
+
+    fn record(&self, elapsed: Duration, result: &Result<String, String>) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.evaluations += 1;
+        metrics.total_time += elapsed;
+        metrics.max_time = metrics.max_time.max(elapsed);
+        if let Err(e) = result {
+            metrics.errors += 1;
+            if e.contains("timed out") {
+                metrics.timeouts += 1;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> JsStats {
+        let metrics = self.metrics.lock().unwrap();
+        let average = if metrics.evaluations > 0 {
+            metrics.total_time.as_secs_f64() * 1000.0 / metrics.evaluations as f64
+        } else {
+            0.0
+        };
+        let evaluations = Long::from(metrics.evaluations);
+        let errors = Long::from(metrics.errors);
+        let timeouts = Long::from(metrics.timeouts);
+        JsStats {
+            evaluations: evaluations.saturating_i32(),
+            evaluations_v2: evaluations,
+            errors: errors.saturating_i32(),
+            errors_v2: errors,
+            timeouts: timeouts.saturating_i32(),
+            timeouts_v2: timeouts,
+            average_ms: average,
+            max_ms: metrics.max_time.as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref JS_POOL: JsPool = JsPool::from_env();
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct JsStats {
+    #[graphql(deprecation = "Use evaluationsV2")]
+    pub evaluations: i32,
+    pub evaluations_v2: Long,
+    #[graphql(deprecation = "Use errorsV2")]
+    pub errors: i32,
+    pub errors_v2: Long,
+    #[graphql(deprecation = "Use timeoutsV2")]
+    pub timeouts: i32,
+    pub timeouts_v2: Long,
+    pub average_ms: f64,
+    pub max_ms: f64,
+}
@@ -1,9 +1,33 @@
+pub mod cache;
 pub mod channel;
+pub mod chunked;
+pub mod counts;
+pub mod dash;
+pub mod decipher;
+pub mod errors;
+pub mod fixtures;
+pub mod formatexpr;
+pub mod hls;
+pub mod httpclient;
+pub mod imgcache;
+pub mod imgproxy;
+pub mod jsruntime;
+pub mod mp4;
+pub mod mux;
 pub mod playlist;
+pub mod proxy;
+pub mod ratelimit;
+pub mod scalars;
 pub mod search;
+pub mod selector;
 pub mod serverrunner;
+pub mod signing;
+pub mod singleflight;
+pub mod streamcache;
+pub mod streaminfo;
 pub mod trending;
 pub mod vidproxy;
+pub mod webm;
 
 use serde::{Deserialize, Serialize};
 
@@ -16,13 +40,18 @@ use juniper::{EmptyMutation, EmptySubscription, FieldError, RootNode};
 use rusty_pipe::youtube_extractor::error::ParsingError;
 use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::str::FromStr;
-use warp::{http::Response, Filter};
+use warp::{http::HeaderMap, http::Response, Filter};
 
 use crate::channel::Channel;
+use crate::dash::dashhandle;
+use crate::hls::hlshandle;
+use crate::imgproxy::imgproxyhandle;
+use crate::mux::muxhandle;
 use crate::playlist::Playlist;
 use crate::trending::Trending;
-use crate::vidproxy::vidproxyhandle;
+use crate::vidproxy::{vidheadhandle, vidproxyhandle};
 use lazy_static::lazy_static;
 use rusty_pipe::utils::utils::fix_thumbnail_url;
 use rusty_pipe::youtube_extractor::channel_extractor::YTChannelExtractor;
@@ -30,7 +59,10 @@ use rusty_pipe::youtube_extractor::playlist_extractor::YTPlaylistExtractor;
 use rusty_pipe::youtube_extractor::trending_extractor::YTTrendingExtractor;
 use serverrunner::*;
 
-pub async fn run_server_async(port_ip: [u8; 4], port: u16) {
+pub async fn run_server_async<D>(port_ip: [u8; 4], port: u16)
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
     let log = warp::log("warp_server");
 
     let homepage = warp::path::end().map(|| {
@@ -43,12 +75,16 @@ pub async fn run_server_async(port_ip: [u8; 4], port: u16) {
 
     log::info!("Listening on 127.0.0.1:8080");
 
-    let state = warp::any().map(move || Context {});
-    let graphql_filter = juniper_warp::make_graphql_filter(schema(), state.boxed());
+    let state = warp::header::headers_cloned()
+        .and(warp::addr::remote())
+        .map(|headers: HeaderMap, remote: Option<SocketAddr>| {
+            Context::from_request(&headers, remote)
+        });
+    let graphql_filter = juniper_warp::make_graphql_filter(schema::<D>(), state.boxed());
 
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_methods(vec!["POST", "GET"])
+        .allow_methods(vec!["POST", "GET", "HEAD"])
         .allow_headers(vec![
             "User-Agent",
             "Sec-Fetch-Mode",
@@ -58,9 +94,53 @@ pub async fn run_server_async(port_ip: [u8; 4], port: u16) {
             "Origin",
             "Access-Control-Request-Method",
             "Access-Control-Request-Headers",
+            "Range",
+            "If-Range",
+        ])
+        .expose_headers(vec![
+            "Content-Range",
+            "Accept-Ranges",
+            "Content-Length",
+            "ETag",
+            "Last-Modified",
         ])
         .build();
-    let vidproxy = warp::path!("vid" / String / u32).and_then(vidproxyhandle);
+    let vidproxy = warp::get()
+        .and(warp::path!("vid" / String / String))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::addr::remote())
+        .and(warp::header::headers_cloned())
+        .and_then(vidproxyhandle::<D>);
+    let vidhead = warp::head()
+        .and(warp::path!("vid" / String / String))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::addr::remote())
+        .and(warp::header::headers_cloned())
+        .and_then(vidheadhandle::<D>);
+    let dash = warp::get()
+        .and(warp::path!("dash" / String))
+        .and(warp::addr::remote())
+        .and(warp::header::headers_cloned())
+        .and_then(dashhandle::<D>);
+    let hls = warp::get()
+        .and(warp::path!("hls" / String / String))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::addr::remote())
+        .and(warp::header::headers_cloned())
+        .and_then(hlshandle::<D>);
+    let mux = warp::get()
+        .and(warp::path!("mux" / String))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::addr::remote())
+        .and(warp::header::headers_cloned())
+        .and_then(muxhandle::<D>);
+    let imgproxy = warp::get()
+        .and(warp::path("img"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::tail())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(imgproxyhandle);
 
     warp::serve(
         warp::get()
@@ -69,6 +149,11 @@ pub async fn run_server_async(port_ip: [u8; 4], port: u16) {
             .or(homepage)
             .or(warp::path("graphql").and(graphql_filter))
             .or(vidproxy)
+            .or(vidhead)
+            .or(imgproxy)
+            .or(dash)
+            .or(hls)
+            .or(mux)
             .with(cors)
             .with(log),
     )
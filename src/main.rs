@@ -30,6 +30,7 @@ use rusty_pipe_server::trending::Trending;
 use rusty_pipe_server::vidproxy::vidproxyhandle;
 
 use rusty_pipe_server::search::*;
+use rusty_pipe_server::serverrunner::DownloaderObj;
 
 use rusty_pipe_server::*;
 
@@ -37,7 +38,7 @@ use rusty_pipe_server::*;
 async fn main() {
     ::std::env::set_var("RUST_LOG", "warp_async");
     env_logger::init();
-    run_server_async(
+    run_server_async::<DownloaderObj>(
         [0, 0, 0, 0],
         std::env::var("PORT")
             .unwrap_or("8080".to_owned())
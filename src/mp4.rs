@@ -0,0 +1,289 @@
+use crate::mux::{Output, Reader};
+use std::convert::TryInto;
+use std::ops::Range;
+
+pub fn read_u16(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?) as u64)
+}
+
+pub fn read_u32(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as u64)
+}
+
+pub fn read_u64(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+fn write_u32(data: &mut [u8], pos: usize, value: u32) -> Result<(), String> {
+    data.get_mut(pos..pos + 4)
+        .ok_or("box too short")?
+        .copy_from_slice(&value.to_be_bytes());
+    Ok(())
+}
+
+fn header_len(data: &[u8], start: usize) -> usize {
+    if read_u32(data, start) == Some(1) {
+        16
+    } else {
+        8
+    }
+}
+
+fn kind(data: &[u8], b: &Range<usize>) -> &[u8] {
+    &data[b.start + 4..b.start + 8]
+}
+
+/// The boxes laid out back to back in `data[within]`.
+fn boxes(data: &[u8], within: Range<usize>) -> Vec<Range<usize>> {
+    let mut found = vec![];
+    let mut pos = within.start;
+    while pos + 8 <= within.end {
+        let size = match read_u32(data, pos) {
+            Some(1) => read_u64(data, pos + 8).unwrap_or(0) as usize,
+            Some(size) => size as usize,
+            None => break,
+        };
+        if size < 8 || pos + size > within.end {
+            break;
+        }
+        found.push(pos..pos + size);
+        pos += size;
+    }
+    found
+}
+
+fn payload(data: &[u8], b: &Range<usize>) -> Range<usize> {
+    b.start + header_len(data, b.start)..b.end
+}
+
+fn child(data: &[u8], parent: &Range<usize>, name: &[u8; 4]) -> Option<Range<usize>> {
+    boxes(data, payload(data, parent))
+        .into_iter()
+        .find(|b| kind(data, b) == name)
+}
+
+/// Descends from `root` through the named container boxes.
+fn find(data: &[u8], root: Range<usize>, path: &[&[u8; 4]]) -> Option<Range<usize>> {
+    path.iter()
+        .try_fold(root, |parent, name| child(data, &parent, name))
+}
+
+fn mp4_box(name: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut b = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+    b.extend_from_slice(name);
+    b.extend_from_slice(payload);
+    b
+}
+
+/// Offset of a full box's field that is 32 bits in version 0 boxes and 64 in version 1
+/// ones, given where it sits in each.
+fn versioned(data: &[u8], b: &Range<usize>, v0: usize, v1: usize) -> Option<usize> {
+    let payload = payload(data, b);
+    match data.get(payload.start)? {
+        1 => Some(payload.start + v1),
+        _ => Some(payload.start + v0),
+    }
+}
+
+/// Reads the next whole box, or `None` at the end of the stream.
+async fn next_box(reader: &mut Reader) -> Result<Option<Vec<u8>>, String> {
+    if !reader.fill(8).await? {
+        return if reader.buffered().is_empty() {
+            Ok(None)
+        } else {
+            Err("truncated box header".to_owned())
+        };
+    }
+    let size = match read_u32(reader.buffered(), 0) {
+        Some(1) => {
+            if !reader.fill(16).await? {
+                return Err("truncated box header".to_owned());
+            }
+            read_u64(reader.buffered(), 8).unwrap_or(0)
+        }
+        Some(0) => return Err("boxes running to the end of the file are not supported".to_owned()),
+        Some(size) => size,
+        None => return Err("truncated box header".to_owned()),
+    };
+    if size < 8 {
+        return Err(format!("invalid box size {}", size));
+    }
+    reader.read(size as usize).await.map(Some)
+}
+
+/// Sets the track id in a `trak`'s `tkhd`.
+fn set_track_id(trak: &mut Vec<u8>, id: u32) -> Result<(), String> {
+    let tkhd = find(trak, 0..trak.len(), &[b"tkhd"]).ok_or("trak without tkhd")?;
+    let pos = versioned(trak, &tkhd, 12, 20).ok_or("short tkhd")?;
+    write_u32(trak, pos, id)
+}
+
+/// Timescale of the first track's media, which fragment decode times count in.
+fn timescale(moov: &[u8]) -> Result<u64, String> {
+    let mdhd =
+        find(moov, 0..moov.len(), &[b"trak", b"mdia", b"mdhd"]).ok_or("moov without mdhd")?;
+    let pos = versioned(moov, &mdhd, 12, 20).ok_or("short mdhd")?;
+    match read_u32(moov, pos) {
+        Some(0) | None => Err("moov without a timescale".to_owned()),
+        Some(timescale) => Ok(timescale),
+    }
+}
+
+/// Combines the single-track `moov`s of the video and audio streams into one describing
+/// video as track 1 and audio as track 2.
+fn merge_moov(video: &[u8], audio: &[u8]) -> Result<Vec<u8>, String> {
+    let audio_root = 0..audio.len();
+    let mut audio_trak =
+        audio[find(audio, audio_root.clone(), &[b"trak"]).ok_or("audio without trak")?].to_vec();
+    set_track_id(&mut audio_trak, 2)?;
+    let mut audio_trex = audio
+        [find(audio, audio_root, &[b"mvex", b"trex"]).ok_or("audio is not fragmented")?]
+    .to_vec();
+    write_u32(&mut audio_trex, 12, 2)?;
+
+    let mut merged = vec![];
+    for b in boxes(video, payload(video, &(0..video.len()))) {
+        let mut copy = video[b.clone()].to_vec();
+        match kind(video, &b) {
+            b"mvhd" => {
+                let next_track_id = copy.len() - 4;
+                write_u32(&mut copy, next_track_id, 3)?;
+                merged.extend_from_slice(&copy);
+            }
+            b"trak" => {
+                set_track_id(&mut copy, 1)?;
+                merged.extend_from_slice(&copy);
+                merged.extend_from_slice(&audio_trak);
+            }
+            b"mvex" => {
+                let mut mvex = vec![];
+                for entry in boxes(&copy, payload(&copy, &(0..copy.len()))) {
+                    let mut entry = copy[entry].to_vec();
+                    if &entry[4..8] == b"trex" {
+                        write_u32(&mut entry, 12, 1)?;
+                    }
+                    mvex.extend_from_slice(&entry);
+                }
+                mvex.extend_from_slice(&audio_trex);
+                merged.extend_from_slice(&mp4_box(b"mvex", &mvex));
+            }
+            _ => merged.extend_from_slice(&copy),
+        }
+    }
+    Ok(mp4_box(b"moov", &merged))
+}
+
+/// A `moof` and its `mdat`, with the decode time of its first sample in seconds.
+struct Fragment {
+    data: Vec<u8>,
+    time: f64,
+}
+
+impl Fragment {
+    /// Renumbers the fragment for the muxed file. Only fixed width fields change, so the
+    /// sample offsets in `trun`, relative to the `moof`, stay valid.
+    fn retag(&mut self, track_id: u32, sequence: u32) -> Result<(), String> {
+        let moof = 0..read_u32(&self.data, 0).unwrap_or(0) as usize;
+        let mfhd = find(&self.data, moof.clone(), &[b"mfhd"]).ok_or("moof without mfhd")?;
+        let tfhd = find(&self.data, moof, &[b"traf", b"tfhd"]).ok_or("moof without tfhd")?;
+        let sequence_pos = payload(&self.data, &mfhd).start + 4;
+        let track_pos = payload(&self.data, &tfhd).start + 4;
+        write_u32(&mut self.data, sequence_pos, sequence)?;
+        write_u32(&mut self.data, track_pos, track_id)
+    }
+}
+
+struct Track {
+    reader: Reader,
+    timescale: u64,
+    next: Option<Fragment>,
+}
+
+impl Track {
+    /// Reads up to the `moov`, returning it and the `ftyp` before it.
+    async fn open(mut reader: Reader) -> Result<(Self, Option<Vec<u8>>, Vec<u8>), String> {
+        let mut ftyp = None;
+        let moov = loop {
+            let b = next_box(&mut reader).await?.ok_or("stream without moov")?;
+            match &b[4..8] {
+                b"ftyp" => ftyp = Some(b),
+                b"moov" => break b,
+                _ => {}
+            }
+        };
+        let track = Track {
+            reader,
+            timescale: timescale(&moov)?,
+            next: None,
+        };
+        Ok((track, ftyp, moov))
+    }
+
+    async fn advance(&mut self) -> Result<(), String> {
+        self.next = None;
+        let moof = loop {
+            match next_box(&mut self.reader).await? {
+                None => return Ok(()),
+                Some(b) if &b[4..8] == b"moof" => break b,
+                // sidx, styp and emsg describe the source file only.
+                Some(_) => {}
+            }
+        };
+        let mdat = loop {
+            match next_box(&mut self.reader).await? {
+                None => return Err("moof without mdat".to_owned()),
+                Some(b) if &b[4..8] == b"mdat" => break b,
+                Some(_) => {}
+            }
+        };
+        let tfdt = find(&moof, 0..moof.len(), &[b"traf", b"tfdt"]).ok_or("moof without tfdt")?;
+        let start = payload(&moof, &tfdt).start;
+        let version = *moof.get(start).ok_or("short tfdt")?;
+        let pos = start + 4;
+        let decode_time = if version == 1 {
+            read_u64(&moof, pos)
+        } else {
+            read_u32(&moof, pos)
+        }
+        .ok_or("short tfdt")?;
+        let mut data = moof;
+        data.extend_from_slice(&mdat);
+        self.next = Some(Fragment {
+            data,
+            time: decode_time as f64 / self.timescale as f64,
+        });
+        Ok(())
+    }
+}
+
+/// Muxes two fragmented MP4 streams into one, sending fragments in decode order as
+/// they arrive. Only one fragment per stream is held in memory.
+pub async fn mux(video: Reader, audio: Reader, output: &mut Output) -> Result<(), String> {
+    let (mut video, ftyp, video_moov) = Track::open(video).await?;
+    let (mut audio, _, audio_moov) = Track::open(audio).await?;
+    let mut head = ftyp.unwrap_or_default();
+    head.extend_from_slice(&merge_moov(&video_moov, &audio_moov)?);
+    output.send(head).await?;
+
+    video.advance().await?;
+    audio.advance().await?;
+    let mut sequence = 1;
+    loop {
+        let video_first = match (&video.next, &audio.next) {
+            (None, None) => return Ok(()),
+            (Some(v), Some(a)) => v.time <= a.time,
+            (v, _) => v.is_some(),
+        };
+        let (track, track_id) = if video_first {
+            (&mut video, 1)
+        } else {
+            (&mut audio, 2)
+        };
+        if let Some(mut fragment) = track.next.take() {
+            fragment.retag(track_id, sequence)?;
+            sequence += 1;
+            output.send(fragment.data).await?;
+        }
+        track.advance().await?;
+    }
+}
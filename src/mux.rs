@@ -0,0 +1,188 @@
+use crate::errors::{error_response, ErrorCode};
+use crate::httpclient::env_or;
+use crate::proxy::PROXY_POOL;
+use crate::signing::{authorize, mux_stream};
+use crate::vidproxy::{fetch, resolve};
+use crate::{mp4, webm};
+use futures::stream::{Stream, StreamExt};
+use rusty_pipe::downloader_trait::Downloader;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use warp::http::header::{self, HeaderMap};
+use warp::http::{Method, Response};
+use warp::hyper::body::{Bytes, Sender};
+use warp::hyper::Body;
+
+/// An upstream body buffered just enough to take whole boxes or elements off its front.
+pub struct Reader {
+    stream: Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>> + Send>>,
+    buf: Vec<u8>,
+    max_unit: usize,
+}
+
+impl Reader {
+    fn new(resp: reqwest::Response) -> Self {
+        Reader {
+            stream: Box::pin(resp.bytes_stream()),
+            buf: vec![],
+            max_unit: env_or("MUX_MAX_UNIT_BYTES", 64 * 1024 * 1024),
+        }
+    }
+
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Buffers at least `n` bytes, returning false if the stream ends first.
+    pub async fn fill(&mut self, n: usize) -> Result<bool, String> {
+        if n > self.max_unit {
+            return Err(format!("{} byte unit exceeds MUX_MAX_UNIT_BYTES", n));
+        }
+        while self.buf.len() < n {
+            match self.stream.next().await {
+                Some(Ok(chunk)) => self.buf.extend_from_slice(&chunk),
+                Some(Err(e)) => return Err(e.to_string()),
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Takes `n` bytes that have already been buffered.
+    pub fn take(&mut self, n: usize) -> Vec<u8> {
+        let rest = self.buf.split_off(n);
+        std::mem::replace(&mut self.buf, rest)
+    }
+
+    /// Buffers and takes `n` bytes.
+    pub async fn read(&mut self, n: usize) -> Result<Vec<u8>, String> {
+        if !self.fill(n).await? {
+            return Err("stream ended mid unit".to_owned());
+        }
+        Ok(self.take(n))
+    }
+}
+
+/// The muxed response body; sending waits while the client is not reading.
+pub struct Output(Sender);
+
+impl Output {
+    pub async fn send(&mut self, data: Vec<u8>) -> Result<(), String> {
+        self.0
+            .send_data(Bytes::from(data))
+            .await
+            .map_err(|_| "client went away".to_owned())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Container {
+    Mp4,
+    WebM,
+}
+
+fn container(mime_type: &str) -> Option<Container> {
+    let kind = mime_type.split(';').next().unwrap_or_default().trim();
+    if kind.ends_with("/mp4") {
+        Some(Container::Mp4)
+    } else if kind.ends_with("/webm") {
+        Some(Container::WebM)
+    } else {
+        None
+    }
+}
+
+/// Resolves and opens one of the two streams being muxed.
+async fn open<D>(video_id: &str, itag: u32) -> Result<(Reader, Container), Response<Body>>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    let (stream, _) = resolve::<D>(video_id, itag, false).await?;
+    let container = container(&stream.mime_type).ok_or_else(|| {
+        error_response(
+            ErrorCode::BadRequest,
+            format!("itag {} is neither mp4 nor webm", itag),
+        )
+    })?;
+    let proxy = PROXY_POOL.for_stream(video_id);
+    let resp = fetch(Method::GET, proxy, &stream.url, &HeaderMap::new()).await?;
+    if !resp.status().is_success() {
+        return Err(error_response(
+            ErrorCode::UpstreamError,
+            format!("itag {} answered {}", itag, resp.status()),
+        ));
+    }
+    Ok((Reader::new(resp), container))
+}
+
+/// Serves `/mux/{video_id}?video={itag}&audio={itag}`: the two streams remuxed into one
+/// fragmented MP4 or WebM as they download. The signature covers both itags, see
+/// `UrlSigner::mux_url`.
+pub async fn muxhandle<D>(
+    video_id: String,
+    query: HashMap<String, String>,
+    remote: Option<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, warp::Rejection>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    let itag = |name: &str| query.get(name).and_then(|itag| itag.parse::<u32>().ok());
+    let (video_itag, audio_itag) = match (itag("video"), itag("audio")) {
+        (Some(video), Some(audio)) => (video, audio),
+        _ => {
+            return Ok(error_response(
+                ErrorCode::BadRequest,
+                "video and audio itags are required",
+            ))
+        }
+    };
+    let stream = mux_stream(video_itag, audio_itag);
+    if let Err(resp) = authorize(&video_id, &stream, &query, remote, &headers) {
+        return Ok(resp);
+    }
+    let (video, video_container) = match open::<D>(&video_id, video_itag).await {
+        Ok(opened) => opened,
+        Err(resp) => return Ok(resp),
+    };
+    let (audio, audio_container) = match open::<D>(&video_id, audio_itag).await {
+        Ok(opened) => opened,
+        Err(resp) => return Ok(resp),
+    };
+    if video_container != audio_container {
+        return Ok(error_response(
+            ErrorCode::BadRequest,
+            "video and audio must both be mp4 or both be webm",
+        ));
+    }
+
+    let (sender, body) = Body::channel();
+    let mut output = Output(sender);
+    tokio::spawn(async move {
+        let muxed = match video_container {
+            Container::Mp4 => mp4::mux(video, audio, &mut output).await,
+            Container::WebM => webm::mux(video, audio, &mut output).await,
+        };
+        if let Err(e) = muxed {
+            log::warn!(
+                "muxing {} {}+{} stopped: {}",
+                video_id,
+                video_itag,
+                audio_itag,
+                e
+            );
+            output.0.abort();
+        }
+    });
+    Ok(Response::builder()
+        .header(
+            header::CONTENT_TYPE,
+            match video_container {
+                Container::Mp4 => "video/mp4",
+                Container::WebM => "video/webm",
+            },
+        )
+        .body(body)
+        .unwrap_or_else(|e| error_response(ErrorCode::UpstreamError, e)))
+}
@@ -1,32 +1,35 @@
+use crate::counts::count;
+use crate::errors::field_error;
+use crate::scalars::Long;
 use crate::search::VideoResult;
 use crate::{Context, Thumbnail};
 use juniper::FieldError;
-use rusty_pipe::utils::utils::fix_thumbnail_url;
 use rusty_pipe::youtube_extractor::playlist_extractor::YTPlaylistExtractor;
+use std::sync::Arc;
 
 pub struct Playlist {
-    pub extractor: YTPlaylistExtractor,
+    pub extractor: Arc<YTPlaylistExtractor>,
 }
 
 #[juniper::graphql_object(Context = Context)]
 impl Playlist {
     fn name(&self) -> Result<String, FieldError> {
-        Ok(self.extractor.get_name()?)
+        Ok(self.extractor.get_name().map_err(field_error)?)
     }
 
     fn uploader_name(&self) -> Result<String, FieldError> {
-        Ok(self.extractor.get_uploader_name()?)
+        Ok(self.extractor.get_uploader_name().map_err(field_error)?)
     }
 
     fn uploader_url(&self) -> Result<String, FieldError> {
-        Ok(self.extractor.get_uploader_url()?)
+        Ok(self.extractor.get_uploader_url().map_err(field_error)?)
     }
 
-    fn uploader_avatars(&self) -> Result<Vec<Thumbnail>, FieldError> {
+    fn uploader_avatars(&self, context: &Context) -> Result<Vec<Thumbnail>, FieldError> {
         let mut avatars = vec![];
-        for avatar in self.extractor.get_uploader_avatars()? {
+        for avatar in self.extractor.get_uploader_avatars().map_err(field_error)? {
             avatars.push(Thumbnail {
-                url: fix_thumbnail_url(&avatar.url),
+                url: context.image_url(&avatar.url),
                 width: avatar.width as i32,
                 height: avatar.height as i32,
             })
@@ -34,11 +37,11 @@ impl Playlist {
         Ok(avatars)
     }
 
-    fn thumbnails(&self) -> Result<Vec<Thumbnail>, FieldError> {
+    fn thumbnails(&self, context: &Context) -> Result<Vec<Thumbnail>, FieldError> {
         let mut thumbnails = vec![];
-        for thumb in self.extractor.get_thumbnails()? {
+        for thumb in self.extractor.get_thumbnails().map_err(field_error)? {
             thumbnails.push(Thumbnail {
-                url: fix_thumbnail_url(&thumb.url),
+                url: context.image_url(&thumb.url),
                 width: thumb.width as i32,
                 height: thumb.height as i32,
             })
@@ -47,33 +50,46 @@ impl Playlist {
     }
 
     fn next_page_url(&self) -> Result<Option<String>, FieldError> {
-        Ok(self.extractor.get_next_page_url()?)
+        Ok(self.extractor.get_next_page_url().map_err(field_error)?)
     }
 
+    #[graphql(deprecation = "Use videosCountV2")]
     fn videos_count(&self) -> Result<i32, FieldError> {
-        Ok(self.extractor.get_stream_count()?)
+        Ok(self.videos_count_v2()?.saturating_i32())
     }
 
-    fn videos(&self) -> Result<Vec<VideoResult>, FieldError> {
+    fn videos_count_v2(&self) -> Result<Long, FieldError> {
+        Ok(Long::from(
+            self.extractor.get_stream_count().map_err(field_error)?,
+        ))
+    }
+
+    fn videos(&self, context: &Context) -> Result<Vec<VideoResult>, FieldError> {
         let mut videos = vec![];
-        for vid in self.extractor.get_videos()? {
+        for vid in self.extractor.get_videos().map_err(field_error)? {
+            let duration = vid.get_duration().ok();
+            let view_count = count(&vid.video_info, &["viewCountText", "shortViewCountText"])
+                .or_else(|| vid.get_view_count().ok().map(Long::from));
             videos.push(VideoResult {
-                name: vid.get_name()?,
-                video_id: vid.video_id()?,
+                name: vid.get_name().map_err(field_error)?,
+                video_id: vid.video_id().map_err(field_error)?,
                 is_ad: vid.is_ad().unwrap_or(false),
                 is_premium_video: vid.is_premium_video().unwrap_or(false),
-                url: vid.get_url()?,
+                url: vid.get_url().map_err(field_error)?,
                 is_live: vid.is_live().unwrap_or(false),
-                duration: vid.get_duration().ok(),
+                duration,
+                duration_v2: duration.map(Long::from),
                 uploader_name: vid.get_uploader_name().ok(),
                 uploader_url: vid.get_uploader_url().ok(),
                 upload_date: vid.get_textual_upload_date().ok(),
-                view_count: vid.get_view_count().ok(),
+                view_count: view_count.map(Long::saturating_i32),
+                view_count_v2: view_count,
                 thumbnail: vid
-                    .get_thumbnails()?
+                    .get_thumbnails()
+                    .map_err(field_error)?
                     .iter()
                     .map(|f| Thumbnail {
-                        url: fix_thumbnail_url(&f.url),
+                        url: context.image_url(&f.url),
                         width: f.width as i32,
                         height: f.height as i32,
                     })
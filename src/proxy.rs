@@ -0,0 +1,208 @@
+use crate::httpclient::{env_or, CLIENT_CONFIG};
+use lazy_static::lazy_static;
+use lru::LruCache;
+use reqwest::Client;
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    static VIDEO_ID: String;
+}
+
+/// Runs `f` with every upstream request it makes attributed to `video_id`, so the
+/// proxy picked for its extraction is remembered for the later `/vid` fetches.
+pub async fn for_video<F: Future>(video_id: &str, f: F) -> F::Output {
+    VIDEO_ID.scope(video_id.to_owned(), f).await
+}
+
+fn current_video() -> Option<String> {
+    VIDEO_ID.try_with(|id| id.clone()).ok()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Rotation {
+    /// Every request goes to the next healthy proxy.
+    RoundRobin,
+    /// Every request made for one video id goes through the same proxy.
+    Sticky,
+    /// The first healthy proxy in configured order is used until it fails.
+    Failover,
+}
+
+impl Rotation {
+    fn from_env() -> Self {
+        match std::env::var("PROXY_ROTATION")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "sticky" => Rotation::Sticky,
+            "failover" => Rotation::Failover,
+            _ => Rotation::RoundRobin,
+        }
+    }
+}
+
+pub struct UpstreamProxy {
+    pub url: String,
+    pub client: Client,
+    pub media_client: Client,
+    failures: AtomicU32,
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+impl UpstreamProxy {
+    fn new(url: Option<&str>) -> reqwest::Result<Self> {
+        Ok(UpstreamProxy {
+            url: url.unwrap_or("direct").to_owned(),
+            client: CLIENT_CONFIG.build_client(url)?,
+            media_client: CLIENT_CONFIG.build_media_client(url)?,
+            failures: AtomicU32::new(0),
+            unhealthy_until: Mutex::new(None),
+        })
+    }
+
+    fn is_healthy(&self) -> bool {
+        match *self.unhealthy_until.lock().unwrap() {
+            Some(until) => until <= Instant::now(),
+            None => true,
+        }
+    }
+}
+
+/// The set of routes upstream traffic can leave through. Without `UPSTREAM_PROXIES`
+/// this holds a single direct connection.
+pub struct ProxyPool {
+    proxies: Vec<UpstreamProxy>,
+    rotation: Rotation,
+    cooldown: Duration,
+    next: AtomicUsize,
+    videos: Mutex<LruCache<String, usize>>,
+}
+
+impl ProxyPool {
+    pub fn from_env() -> Self {
+        let urls: Vec<String> = std::env::var("UPSTREAM_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|url| url.trim().to_owned())
+            .filter(|url| !url.is_empty())
+            .collect();
+        let mut proxies = vec![];
+        for url in &urls {
+            match UpstreamProxy::new(Some(url)) {
+                Ok(proxy) => proxies.push(proxy),
+                Err(e) => log::error!("ignoring upstream proxy {}: {}", url, e),
+            }
+        }
+        if proxies.is_empty() {
+            proxies.push(UpstreamProxy::new(None).expect("Cant build upstream http client"));
+        }
+        ProxyPool {
+            proxies,
+            rotation: Rotation::from_env(),
+            cooldown: Duration::from_secs(env_or("PROXY_COOLDOWN_SECS", 60)),
+            next: AtomicUsize::new(0),
+            videos: Mutex::new(LruCache::new(env_or("PROXY_STICKY_VIDEOS", 4096))),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.proxies.len()
+    }
+
+    pub fn get(&self, index: usize) -> &UpstreamProxy {
+        &self.proxies[index]
+    }
+
+    pub fn any_healthy(&self) -> bool {
+        self.proxies.iter().any(UpstreamProxy::is_healthy)
+    }
+
+    fn next_healthy(&self) -> usize {
+        let len = self.proxies.len();
+        let start = match self.rotation {
+            Rotation::Failover => 0,
+            _ => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        (0..len)
+            .map(|offset| (start + offset) % len)
+            .find(|&index| self.proxies[index].is_healthy())
+            .unwrap_or(start % len)
+    }
+
+    /// Picks the proxy for the next upstream request of the current task.
+    pub fn pick(&self) -> usize {
+        let video = current_video();
+        if self.rotation != Rotation::Sticky {
+            return self.next_healthy();
+        }
+        if let Some(index) = video.as_ref().and_then(|v| self.sticky(v)) {
+            return index;
+        }
+        let index = self.next_healthy();
+        if let Some(video) = video {
+            self.videos.lock().unwrap().put(video, index);
+        }
+        index
+    }
+
+    /// Records that `index` downloaded `url` for the current task's video. Watch pages
+    /// and player responses hand out stream URLs bound to the IP that fetched them, so
+    /// their proxy is the one `for_stream` must use.
+    pub fn report_page(&self, index: usize, url: &str) {
+        let binds_streams = url.contains("/watch")
+            || url.contains("get_video_info")
+            || url.contains("/youtubei/v1/player");
+        if let (true, Some(video)) = (binds_streams, current_video()) {
+            self.videos.lock().unwrap().put(video, index);
+        }
+    }
+
+    fn sticky(&self, video_id: &str) -> Option<usize> {
+        let index = *self.videos.lock().unwrap().get(video_id)?;
+        if self.proxies[index].is_healthy() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// The proxy to fetch `video_id`'s streams through; googlevideo URLs are bound to
+    /// the IP that extracted them, so this is whichever proxy did the extraction.
+    pub fn for_stream(&self, video_id: &str) -> usize {
+        match self.videos.lock().unwrap().get(video_id) {
+            Some(index) => *index,
+            None => self.next_healthy(),
+        }
+    }
+
+    pub fn report_success(&self, index: usize) {
+        let proxy = &self.proxies[index];
+        proxy.failures.store(0, Ordering::Relaxed);
+        *proxy.unhealthy_until.lock().unwrap() = None;
+    }
+
+    /// Takes a proxy out of rotation for a cool-down that grows with consecutive failures.
+    pub fn report_failure(&self, index: usize) {
+        if self.proxies.len() == 1 {
+            return;
+        }
+        let proxy = &self.proxies[index];
+        let failures = proxy.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        let cooldown = self.cooldown * failures.min(10);
+        log::warn!(
+            "upstream proxy {} failed {} times, resting for {:?}",
+            proxy.url,
+            failures,
+            cooldown
+        );
+        *proxy.unhealthy_until.lock().unwrap() = Some(Instant::now() + cooldown);
+    }
+}
+
+lazy_static! {
+    pub static ref PROXY_POOL: ProxyPool = ProxyPool::from_env();
+}
@@ -0,0 +1,234 @@
+use crate::httpclient::env_or;
+use lazy_static::lazy_static;
+use rusty_pipe::youtube_extractor::error::ParsingError;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const RATE_LIMITED: &str = "RATE_LIMITED";
+
+/// Why an upstream download failed. `ParsingError` belongs to the extractor and only
+/// carries text, so the failure leads the cause of the `DownloadError` made by `error`
+/// and `of` reads it back.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DownloadFailure {
+    /// Upstream throttled us or served a bot check, or the breaker is open.
+    RateLimited,
+    /// Upstream answered with this HTTP status.
+    Status(u16),
+}
+
+impl DownloadFailure {
+    pub fn error(self, detail: impl ToString) -> ParsingError {
+        let tag = match self {
+            DownloadFailure::RateLimited => RATE_LIMITED.to_owned(),
+            DownloadFailure::Status(status) => format!("HTTP {}", status),
+        };
+        ParsingError::DownloadError {
+            cause: format!("{}: {}", tag, detail.to_string()),
+        }
+    }
+
+    /// The failure `err` was made for by `error`, if any.
+    pub fn of(err: &ParsingError) -> Option<Self> {
+        let cause = match err {
+            ParsingError::DownloadError { cause } => cause,
+            _ => return None,
+        };
+        let tag = cause.split(':').next()?;
+        if tag == RATE_LIMITED {
+            return Some(DownloadFailure::RateLimited);
+        }
+        tag.strip_prefix("HTTP ")?
+            .parse()
+            .ok()
+            .map(DownloadFailure::Status)
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket holds at least one token, as a request could never be let through
+    /// by a smaller burst.
+    fn new(rate: f64, burst: f64) -> Self {
+        let burst = burst.max(1.0);
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// How long until a token is available; zero when one can be taken now.
+    fn wait(&mut self, now: Instant) -> Duration {
+        if self.rate <= 0.0 {
+            return Duration::from_secs(0);
+        }
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+        }
+    }
+
+    fn take(&mut self) {
+        if self.rate > 0.0 {
+            self.tokens -= 1.0;
+        }
+    }
+}
+
+struct Buckets {
+    global: TokenBucket,
+    hosts: HashMap<String, TokenBucket>,
+}
+
+/// Token buckets limiting outbound requests overall and per upstream host.
+/// A rate of zero disables the corresponding limit.
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+    host_rate: f64,
+    host_burst: f64,
+}
+
+impl RateLimiter {
+    pub fn from_env() -> Self {
+        let global_rate = env_or("RATE_LIMIT_GLOBAL_RPS", 20.0);
+        RateLimiter {
+            buckets: Mutex::new(Buckets {
+                global: TokenBucket::new(global_rate, env_or("RATE_LIMIT_GLOBAL_BURST", 40.0)),
+                hosts: HashMap::new(),
+            }),
+            host_rate: env_or("RATE_LIMIT_HOST_RPS", 10.0),
+            host_burst: env_or("RATE_LIMIT_HOST_BURST", 20.0),
+        }
+    }
+
+    pub async fn acquire(&self, host: &str) {
+        loop {
+            let wait = {
+                let now = Instant::now();
+                let mut buckets = self.buckets.lock().unwrap();
+                let Buckets { global, hosts } = &mut *buckets;
+                let (host_rate, host_burst) = (self.host_rate, self.host_burst);
+                let host = hosts
+                    .entry(host.to_owned())
+                    .or_insert_with(|| TokenBucket::new(host_rate, host_burst));
+                let wait = global.wait(now).max(host.wait(now));
+                if wait == Duration::from_secs(0) {
+                    global.take();
+                    host.take();
+                }
+                wait
+            };
+            if wait == Duration::from_secs(0) {
+                return;
+            }
+            tokio::time::delay_for(wait).await;
+        }
+    }
+}
+
+/// Stops new upstream traffic for a cool-down once upstream keeps throttling us:
+/// `CIRCUIT_THRESHOLD` throttled answers within `CIRCUIT_WINDOW_SECS` trip it.
+pub struct CircuitBreaker {
+    open_until: Mutex<Option<Instant>>,
+    cooldown: Duration,
+    throttles: Mutex<VecDeque<Instant>>,
+    threshold: usize,
+    window: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn from_env() -> Self {
+        CircuitBreaker {
+            open_until: Mutex::new(None),
+            cooldown: Duration::from_secs(env_or("CIRCUIT_COOLDOWN_SECS", 120)),
+            throttles: Mutex::new(VecDeque::new()),
+            threshold: env_or("CIRCUIT_THRESHOLD", 5usize).max(1),
+            window: Duration::from_secs(env_or("CIRCUIT_WINDOW_SECS", 60)),
+        }
+    }
+
+    /// Counts a throttled answer, tripping the breaker once there have been enough of
+    /// them lately. Returns whether it tripped.
+    pub fn throttled(&self) -> bool {
+        let now = Instant::now();
+        let mut throttles = self.throttles.lock().unwrap();
+        while throttles
+            .front()
+            .map_or(false, |&at| now.saturating_duration_since(at) > self.window)
+        {
+            throttles.pop_front();
+        }
+        throttles.push_back(now);
+        if throttles.len() < self.threshold {
+            return false;
+        }
+        throttles.clear();
+        drop(throttles);
+        self.trip();
+        true
+    }
+
+    pub fn trip(&self) {
+        log::warn!(
+            "upstream is rate limiting, pausing extractions for {:?}",
+            self.cooldown
+        );
+        *self.open_until.lock().unwrap() = Some(Instant::now() + self.cooldown);
+    }
+
+    /// Time left before upstream may be contacted again, if the breaker is open.
+    pub fn retry_after(&self) -> Option<Duration> {
+        let mut open_until = self.open_until.lock().unwrap();
+        match *open_until {
+            Some(until) if until > Instant::now() => Some(until - Instant::now()),
+            Some(_) => {
+                *open_until = None;
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// The error to short-circuit with while the breaker is open.
+    pub fn check(&self) -> Result<(), ParsingError> {
+        match self.retry_after() {
+            Some(wait) => Err(rate_limited(format!(
+                "circuit open, retry in {}s",
+                wait.as_secs() + 1
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref RATE_LIMITER: RateLimiter = RateLimiter::from_env();
+    pub static ref CIRCUIT_BREAKER: CircuitBreaker = CircuitBreaker::from_env();
+}
+
+pub fn rate_limited(detail: impl ToString) -> ParsingError {
+    DownloadFailure::RateLimited.error(detail)
+}
+
+/// Whether an upstream answer is a consent interstitial or a bot check rather than
+/// the page that was asked for. Only the bot check's own form counts: watch pages
+/// mention reCAPTCHA in their scripts.
+pub fn is_blocked_page(final_url: &reqwest::Url, body: &str) -> bool {
+    let host = final_url.host_str().unwrap_or_default();
+    host.starts_with("consent.")
+        || final_url.path().starts_with("/sorry")
+        || body.contains(r#"<form id="captcha-form""#)
+}
@@ -0,0 +1,53 @@
+use juniper::{InputValue, ParseScalarResult, ParseScalarValue, ScalarToken, ScalarValue, Value};
+
+/// A 64-bit integer for counts, durations and sizes. GraphQL's `Int` is 32 bits, which
+/// view counts of popular videos already overflow.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Long(pub i64);
+
+impl Long {
+    /// Clamps to the 32-bit range of the deprecated `Int` fields instead of wrapping.
+    pub fn saturating_i32(self) -> i32 {
+        self.0.max(i32::MIN as i64).min(i32::MAX as i64) as i32
+    }
+}
+
+impl From<i32> for Long {
+    fn from(value: i32) -> Self {
+        Long(value.into())
+    }
+}
+
+impl From<u64> for Long {
+    /// Saturates at `i64::MAX`.
+    fn from(value: u64) -> Self {
+        Long(value.min(i64::MAX as u64) as i64)
+    }
+}
+
+#[juniper::graphql_scalar(
+    description = "A 64-bit integer, sent as a decimal string since JSON numbers lose precision past 2^53"
+)]
+impl<S> GraphQLScalar for Long
+where
+    S: ScalarValue,
+{
+    fn resolve(&self) -> Value {
+        Value::scalar(self.0.to_string())
+    }
+
+    fn from_input_value(value: &InputValue) -> Option<Long> {
+        value
+            .as_string_value()
+            .and_then(|value| value.parse().ok())
+            .or_else(|| value.as_int_value().map(i64::from))
+            .map(Long)
+    }
+
+    fn from_str<'a>(value: ScalarToken<'a>) -> ParseScalarResult<'a, S> {
+        match value {
+            ScalarToken::Int(_) => <i32 as ParseScalarValue<S>>::from_str(value),
+            _ => <String as ParseScalarValue<S>>::from_str(value),
+        }
+    }
+}
@@ -1,84 +1,111 @@
 use juniper::FieldError;
 
 use super::Context;
-use crate::serverrunner::{DownloaderObj, Thumbnail};
-use rusty_pipe::utils::utils::fix_thumbnail_url;
+use crate::counts::count;
+use crate::errors::field_error;
+use crate::scalars::Long;
+use crate::serverrunner::Thumbnail;
+use rusty_pipe::downloader_trait::Downloader;
 use rusty_pipe::youtube_extractor::search_extractor::{YTSearchExtractor, YTSearchItem};
+use std::marker::PhantomData;
+use std::sync::Arc;
 
-pub struct Search {
-    pub extractor: YTSearchExtractor,
+pub struct Search<D> {
+    pub extractor: Arc<YTSearchExtractor>,
+    pub downloader: PhantomData<D>,
 }
 #[juniper::graphql_object(Context = Context)]
-impl Search {
+impl<D> Search<D>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
     async fn suggestion(&self) -> Result<Vec<String>, FieldError> {
-        Ok(
-            YTSearchExtractor::get_search_suggestion::<DownloaderObj>("")
-                .await
-                .map_err(|e| format!("{:#?}", e))?,
-        )
+        Ok(YTSearchExtractor::get_search_suggestion::<D>("")
+            .await
+            .map_err(field_error)?)
     }
 
-    fn result(&self) -> Result<Vec<SearchResult>, FieldError> {
+    fn result(&self, context: &Context) -> Result<Vec<SearchResult>, FieldError> {
         let mut result = vec![];
-        for item in self.extractor.search_results()? {
+        for item in self.extractor.search_results().map_err(field_error)? {
             result.push(match item {
-                YTSearchItem::StreamInfoItem(vid) => SearchResult::VideoInfo(VideoResult {
-                    name: vid.get_name()?,
-                    video_id: vid.video_id()?,
-                    is_ad: vid.is_ad().unwrap_or(false),
-                    is_premium_video: vid.is_premium_video().unwrap_or(false),
-                    url: vid.get_url()?,
-                    is_live: vid.is_live().unwrap_or(false),
-                    duration: vid.get_duration().ok(),
-                    uploader_name: vid.get_uploader_name().ok(),
-                    uploader_url: vid.get_uploader_url().ok(),
-                    upload_date: vid.get_textual_upload_date().ok(),
-                    view_count: vid.get_view_count().ok(),
-                    thumbnail: vid
-                        .get_thumbnails()?
-                        .iter()
-                        .map(|f| Thumbnail {
-                            url: fix_thumbnail_url(&f.url),
-                            width: f.width as i32,
-                            height: f.height as i32,
-                        })
-                        .collect(),
-                }),
+                YTSearchItem::StreamInfoItem(vid) => {
+                    let duration = vid.get_duration().ok();
+                    let view_count =
+                        count(&vid.video_info, &["viewCountText", "shortViewCountText"])
+                            .or_else(|| vid.get_view_count().ok().map(Long::from));
+                    SearchResult::VideoInfo(VideoResult {
+                        name: vid.get_name().map_err(field_error)?,
+                        video_id: vid.video_id().map_err(field_error)?,
+                        is_ad: vid.is_ad().unwrap_or(false),
+                        is_premium_video: vid.is_premium_video().unwrap_or(false),
+                        url: vid.get_url().map_err(field_error)?,
+                        is_live: vid.is_live().unwrap_or(false),
+                        duration,
+                        duration_v2: duration.map(Long::from),
+                        uploader_name: vid.get_uploader_name().ok(),
+                        uploader_url: vid.get_uploader_url().ok(),
+                        upload_date: vid.get_textual_upload_date().ok(),
+                        view_count: view_count.map(Long::saturating_i32),
+                        view_count_v2: view_count,
+                        thumbnail: vid
+                            .get_thumbnails()
+                            .map_err(field_error)?
+                            .iter()
+                            .map(|f| Thumbnail {
+                                url: context.image_url(&f.url),
+                                width: f.width as i32,
+                                height: f.height as i32,
+                            })
+                            .collect(),
+                    })
+                }
                 YTSearchItem::ChannelInfoItem(channel) => {
+                    let subscribers = count(&channel.channel_info, &["subscriberCountText"])
+                        .or_else(|| channel.get_subscriber_count().ok().map(Long::from));
+                    let videos = count(&channel.channel_info, &["videoCountText"])
+                        .or_else(|| channel.get_stream_count().ok().map(Long::from));
                     SearchResult::ChannelInfo(ChannelResult {
-                        name: channel.get_name()?,
-                        channel_id: channel.channel_id()?,
+                        name: channel.get_name().map_err(field_error)?,
+                        channel_id: channel.channel_id().map_err(field_error)?,
                         thumbnail: channel
-                            .get_thumbnails()?
+                            .get_thumbnails()
+                            .map_err(field_error)?
                             .iter()
                             .map(|f| Thumbnail {
-                                url: fix_thumbnail_url(&f.url),
+                                url: context.image_url(&f.url),
                                 width: f.width as i32,
                                 height: f.height as i32,
                             })
                             .collect(),
-                        url: channel.get_url()?,
-                        subscribers: channel.get_subscriber_count().ok(),
-                        videos: channel.get_stream_count().ok(),
-                        description: channel.get_description()?,
+                        url: channel.get_url().map_err(field_error)?,
+                        subscribers: subscribers.map(Long::saturating_i32),
+                        subscribers_v2: subscribers,
+                        videos: videos.map(Long::saturating_i32),
+                        videos_v2: videos,
+                        description: channel.get_description().map_err(field_error)?,
                     })
                 }
                 YTSearchItem::PlaylistInfoItem(playlist) => {
+                    let videos = count(&playlist.playlist_info, &["videoCount", "videoCountText"])
+                        .or_else(|| playlist.get_stream_count().ok().map(Long::from));
                     SearchResult::PlaylistInfo(PlaylistResult {
-                        name: playlist.get_name()?,
-                        playlist_id: playlist.playlist_id()?,
+                        name: playlist.get_name().map_err(field_error)?,
+                        playlist_id: playlist.playlist_id().map_err(field_error)?,
                         thumbnail: playlist
-                            .get_thumbnails()?
+                            .get_thumbnails()
+                            .map_err(field_error)?
                             .iter()
                             .map(|f| Thumbnail {
-                                url: fix_thumbnail_url(&f.url),
+                                url: context.image_url(&f.url),
                                 width: f.width as i32,
                                 height: f.height as i32,
                             })
                             .collect(),
-                        url: playlist.get_url()?,
+                        url: playlist.get_url().map_err(field_error)?,
                         uploader_name: playlist.get_uploader_name().ok(),
-                        videos: playlist.get_stream_count().ok(),
+                        videos: videos.map(Long::saturating_i32),
+                        videos_v2: videos,
                     })
                 }
             })
@@ -87,7 +114,7 @@ impl Search {
     }
 
     fn next_page_url(&self) -> Result<Option<String>, FieldError> {
-        Ok(self.extractor.get_next_page_url()?)
+        Ok(self.extractor.get_next_page_url().map_err(field_error)?)
     }
 }
 
@@ -99,11 +126,16 @@ pub struct VideoResult {
     pub is_premium_video: bool,
     pub url: String,
     pub is_live: bool,
+    #[graphql(deprecation = "Use durationV2")]
     pub duration: Option<i32>,
+    /// Length in seconds.
+    pub duration_v2: Option<Long>,
     pub uploader_name: Option<String>,
     pub uploader_url: Option<String>,
     pub upload_date: Option<String>,
+    #[graphql(deprecation = "Use viewCountV2")]
     pub view_count: Option<i32>,
+    pub view_count_v2: Option<Long>,
     pub thumbnail: Vec<Thumbnail>,
 }
 
@@ -114,7 +146,9 @@ pub struct PlaylistResult {
     pub thumbnail: Vec<Thumbnail>,
     pub url: String,
     pub uploader_name: Option<String>,
+    #[graphql(deprecation = "Use videosV2")]
     pub videos: Option<i32>,
+    pub videos_v2: Option<Long>,
 }
 
 #[derive(juniper::GraphQLObject)]
@@ -123,8 +157,12 @@ pub struct ChannelResult {
     pub channel_id: String,
     pub thumbnail: Vec<Thumbnail>,
     pub url: String,
+    #[graphql(deprecation = "Use subscribersV2")]
     pub subscribers: Option<i32>,
+    pub subscribers_v2: Option<Long>,
+    #[graphql(deprecation = "Use videosV2")]
     pub videos: Option<i32>,
+    pub videos_v2: Option<Long>,
     pub description: Option<String>,
 }
 
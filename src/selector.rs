@@ -0,0 +1,193 @@
+use crate::dash::{adaptive_streams, AdaptiveStream};
+use crate::errors::{error_response, parsing_error_response, ErrorCode};
+use crate::serverrunner::Extractions;
+use rusty_pipe::downloader_trait::Downloader;
+use rusty_pipe::youtube_extractor::stream_extractor::YTStreamExtractor;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use warp::http::Response;
+use warp::hyper::Body;
+
+/// Which of the extractor's stream lists a stream comes from.
+#[derive(juniper::GraphQLEnum, Clone, Copy, PartialEq)]
+#[graphql(name = "StreamKind")]
+pub enum Kind {
+    Muxed,
+    VideoOnly,
+    AudioOnly,
+}
+
+pub struct Candidate {
+    pub kind: Kind,
+    pub stream: AdaptiveStream,
+}
+
+/// Every stream of an extraction: muxed, video only and audio only.
+pub fn candidates<D>(videx: &YTStreamExtractor<D>) -> Vec<Candidate>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    let lists = vec![
+        (
+            Kind::Muxed,
+            adaptive_streams(videx.get_video_stream().unwrap_or_default()),
+        ),
+        (
+            Kind::VideoOnly,
+            adaptive_streams(videx.get_video_only_stream().unwrap_or_default()),
+        ),
+        (
+            Kind::AudioOnly,
+            adaptive_streams(videx.get_audio_streams().unwrap_or_default()),
+        ),
+    ];
+    lists
+        .into_iter()
+        .flat_map(|(kind, streams)| {
+            streams
+                .into_iter()
+                .map(move |stream| Candidate { kind, stream })
+        })
+        .collect()
+}
+
+/// Orders the better stream first by height, frame rate and bitrate, or the worse one
+/// first if `worst`, and ties by the lower itag so the same extraction always picks the
+/// same stream.
+pub fn rank(a: &Candidate, b: &Candidate, worst: bool) -> Ordering {
+    let quality = |c: &Candidate| {
+        (
+            c.stream.height.unwrap_or(0),
+            c.stream.fps.unwrap_or(0),
+            c.stream.bitrate,
+        )
+    };
+    let better_first = quality(b).cmp(&quality(a));
+    if worst {
+        better_first.reverse()
+    } else {
+        better_first
+    }
+    .then(a.stream.itag.cmp(&b.stream.itag))
+}
+
+/// Query parameters narrowing a selector down, which its signature covers.
+pub const NARROWING_PARAMS: [&str; 3] = ["maxHeight", "codec", "container"];
+
+/// What the signature of a `/vid` URL covers: the itag, or the selector together with
+/// the parameters narrowing it down, so none of them can be changed on a signed URL.
+pub fn signed_stream(stream: &str, query: &HashMap<String, String>) -> String {
+    if stream.parse::<u32>().is_ok() {
+        return stream.to_owned();
+    }
+    let mut signed = stream.to_owned();
+    for name in NARROWING_PARAMS.iter() {
+        if let Some(value) = query.get(*name) {
+            signed.push_str(&format!("&{}={}", name, value));
+        }
+    }
+    signed
+}
+
+/// A quality a `/vid` URL may name instead of an itag: `best` and `worst` for streams
+/// with sound, `video-best`, `video-worst`, `audio-best`, `audio-worst`, or `{height}p`
+/// for the best stream with sound at most that tall. Only the `video-` selectors pick
+/// streams without sound. The `maxHeight`, `codec` and `container` query parameters
+/// narrow any of them down.
+pub struct Selector {
+    audio: bool,
+    muxed: bool,
+    worst: bool,
+    max_height: Option<u32>,
+    codec: Option<String>,
+    container: Option<String>,
+}
+
+impl Selector {
+    pub fn parse(name: &str, query: &HashMap<String, String>) -> Result<Self, String> {
+        let (audio, muxed, worst, mut max_height) = match name {
+            "best" => (false, true, false, None),
+            "worst" => (false, true, true, None),
+            "video-best" => (false, false, false, None),
+            "video-worst" => (false, false, true, None),
+            "audio-best" => (true, false, false, None),
+            "audio-worst" => (true, false, true, None),
+            _ => match name.strip_suffix('p').map(str::parse::<u32>) {
+                Some(Ok(height)) => (false, true, false, Some(height)),
+                _ => return Err(format!("unknown stream selector {:?}", name)),
+            },
+        };
+        if let Some(limit) = query.get("maxHeight") {
+            let limit: u32 = limit
+                .parse()
+                .map_err(|_| format!("maxHeight must be a number, not {:?}", limit))?;
+            max_height = Some(max_height.map_or(limit, |height| height.min(limit)));
+        }
+        let lowercase = |name: &str| query.get(name).map(|value| value.to_lowercase());
+        Ok(Selector {
+            audio,
+            muxed,
+            worst,
+            max_height,
+            codec: lowercase("codec"),
+            container: lowercase("container"),
+        })
+    }
+
+    fn accepts(&self, candidate: &Candidate) -> bool {
+        let stream = &candidate.stream;
+        let (container, codecs) = stream.container_and_codecs();
+        let kind_matches = if self.audio {
+            candidate.kind == Kind::AudioOnly
+        } else if self.muxed {
+            candidate.kind == Kind::Muxed
+        } else {
+            candidate.kind != Kind::AudioOnly
+        };
+        let height_matches = match self.max_height {
+            Some(max) if !self.audio => stream.height.map_or(false, |height| height <= max),
+            _ => true,
+        };
+        let codec_matches = self.codec.as_ref().map_or(true, |codec| {
+            codecs
+                .split(',')
+                .any(|name| name.trim().to_lowercase().starts_with(codec.as_str()))
+        });
+        let container_matches = self.container.as_ref().map_or(true, |wanted| {
+            container.to_lowercase().ends_with(&format!("/{}", wanted))
+        });
+        kind_matches && height_matches && codec_matches && container_matches
+    }
+
+    fn pick(&self, candidates: &[Candidate]) -> Option<u32> {
+        candidates
+            .iter()
+            .filter(|candidate| self.accepts(candidate))
+            .min_by(|a, b| rank(a, b, self.worst))
+            .map(|candidate| candidate.stream.itag)
+    }
+}
+
+/// Resolves the selector a `/vid` URL names to the itag of one of the video's streams,
+/// or the error response to send instead.
+pub(crate) async fn select<D>(
+    video_id: &str,
+    name: &str,
+    query: &HashMap<String, String>,
+) -> Result<u32, Response<Body>>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    let selector =
+        Selector::parse(name, query).map_err(|e| error_response(ErrorCode::BadRequest, e))?;
+    let videx = Extractions::<D>::get().video(video_id).await.map_err(|e| {
+        log::warn!("cant extract {} to select {}: {}", video_id, name, e);
+        parsing_error_response(e)
+    })?;
+    selector.pick(&candidates(&videx)).ok_or_else(|| {
+        error_response(
+            ErrorCode::NotFound,
+            format!("no stream of {} matches {}", video_id, name),
+        )
+    })
+}
@@ -3,17 +3,44 @@ use serde::{Deserialize, Serialize};
 use async_trait::async_trait;
 use rusty_pipe::downloader_trait::Downloader;
 use rusty_pipe::youtube_extractor::search_extractor::{YTSearchExtractor, YTSearchItem};
-use rusty_pipe::youtube_extractor::stream_extractor::YTStreamExtractor;
+use rusty_pipe::youtube_extractor::stream_extractor::{
+    StreamItem as ExtractedStream, YTStreamExtractor,
+};
 
 use juniper::{EmptyMutation, EmptySubscription, FieldError, RootNode};
 use rusty_pipe::youtube_extractor::error::ParsingError;
+use std::any::{Any, TypeId};
 use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::runtime::Handle;
+use warp::http::header::{HeaderMap, HOST};
 use warp::{http::Response, Filter};
 
+use crate::cache::{bypassed, bypassing, CacheStats, PAGE_CACHE};
 use crate::channel::Channel;
+use crate::counts::{count, watch_page_count};
+use crate::decipher::{self, DecipherStats, DECIPHER_CACHE};
+use crate::errors::{bad_request, field_error};
+use crate::fixtures;
+use crate::formatexpr::FormatExpr;
+use crate::httpclient::env_or;
+use crate::imgproxy;
+use crate::jsruntime::{JsStats, JS_POOL};
 use crate::playlist::Playlist;
+use crate::proxy;
+use crate::ratelimit::CIRCUIT_BREAKER;
+use crate::scalars::Long;
+use crate::signing::{client_id, proxy_url, URL_SIGNER};
+use crate::singleflight::SingleFlight;
+use crate::streamcache::url_expiry;
+use crate::streaminfo::{
+    codec, codec_strings, color_info, container, label_fps, Codec, ColorInfo, Container,
+};
 use crate::trending::Trending;
 use crate::vidproxy::vidproxyhandle;
 use lazy_static::lazy_static;
@@ -22,127 +49,359 @@ use rusty_pipe::youtube_extractor::channel_extractor::YTChannelExtractor;
 use rusty_pipe::youtube_extractor::playlist_extractor::YTPlaylistExtractor;
 use rusty_pipe::youtube_extractor::trending_extractor::YTTrendingExtractor;
 
-// lazy_static! {
-//     pub static ref download_reqwest_client: reqwest::Client = reqwest::Client::new();
-// }
-
 use crate::search::*;
+use crate::selector::{candidates, Candidate, Kind, Selector};
 // use juniper_warp::
+#[derive(Default)]
 pub struct DownloaderObj;
 
 #[async_trait]
 impl Downloader for DownloaderObj {
     async fn download(url: &str) -> Result<String, ParsingError> {
-        println!("query url : {}", url);
-        let resp = reqwest::Client::new().get(url).send().await.map_err(|er| {
-            ParsingError::DownloadError {
-                cause: er.to_string(),
-            }
-        })?;
-        println!("got response ");
-        let body = resp
-            .text()
-            .await
-            .map_err(|er| ParsingError::DownloadError {
-                cause: er.to_string(),
-            })?;
-        println!("suceess query");
-        Ok(String::from(body))
+        log::debug!("query url : {}", url);
+        DECIPHER_CACHE.lock().unwrap().observe(url);
+        fixtures::download(url, reqwest::header::HeaderMap::new()).await
     }
 
     async fn download_with_header(
         url: &str,
         header: HashMap<String, String>,
     ) -> Result<String, ParsingError> {
-        let client = &reqwest::Client::new();
-        let res = client.get(url);
+        DECIPHER_CACHE.lock().unwrap().observe(url);
         let mut headers = reqwest::header::HeaderMap::new();
         for header in header {
             headers.insert(
                 reqwest::header::HeaderName::from_str(&header.0).map_err(|e| e.to_string())?,
-                header.1.parse().unwrap(),
+                reqwest::header::HeaderValue::from_str(&header.1).map_err(|e| e.to_string())?,
             );
         }
-        let res = res.headers(headers);
-        let res = res.send().await.map_err(|er| er.to_string())?;
-        let body = res.text().await.map_err(|er| er.to_string())?;
-        Ok(String::from(body))
+        fixtures::download(url, headers).await
     }
 
     fn eval_js(script: &str) -> Result<String, String> {
-        use quick_js::{Context, JsValue};
-        let context = Context::new().expect("Cant create js context");
-        // println!("decryption code \n{}",decryption_code);
-        // println!("signature : {}",encrypted_sig);
-        // println!("jscode \n{}", script);
-        let res = context.eval(script).unwrap_or(quick_js::JsValue::Null);
-        // println!("js result : {:?}", result);
-        let result = res.into_string().unwrap_or("".to_string());
-        // print!("JS result: {}", result);
-        Ok(result)
+        decipher::eval_cached(script)
     }
 }
 
 #[derive(Clone)]
-pub struct Context {}
+pub struct Context {
+    /// The requesting client, used to bind signed media URLs.
+    pub client: Option<String>,
+    /// Scheme and host this server is reached at, or `PUBLIC_URL` when set.
+    pub base_url: String,
+    /// `PRIVACY_MODE`: hand out only URLs on this server, never upstream media URLs.
+    pub privacy: bool,
+}
 impl juniper::Context for Context {}
 
-struct Video {
-    extractor: YTStreamExtractor<DownloaderObj>,
+impl Context {
+    pub fn from_request(headers: &HeaderMap, remote: Option<SocketAddr>) -> Self {
+        let base_url = std::env::var("PUBLIC_URL").unwrap_or_else(|_| {
+            let proto = headers
+                .get("x-forwarded-proto")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("http");
+            headers
+                .get(HOST)
+                .and_then(|value| value.to_str().ok())
+                .map(|host| format!("{}://{}", proto, host))
+                .unwrap_or_default()
+        });
+        Context {
+            client: client_id(headers, remote),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            privacy: env_or("PRIVACY_MODE", false),
+        }
+    }
+
+    pub fn proxy_url(&self, video_id: &str, itag: u32) -> String {
+        proxy_url(&self.base_url, video_id, itag, self.client.as_deref())
+    }
+
+    /// The signed `/vid` URL of a quality selector, narrowed down by `narrowing`.
+    pub fn selector_url(
+        &self,
+        video_id: &str,
+        selector: &str,
+        narrowing: &HashMap<String, String>,
+    ) -> String {
+        URL_SIGNER.selector_url(
+            &self.base_url,
+            video_id,
+            selector,
+            narrowing,
+            self.client.as_deref(),
+        )
+    }
+
+    /// The signed `/mux` URL remuxing two streams of `video_id` into one.
+    pub fn mux_url(&self, video_id: &str, video_itag: u32, audio_itag: u32) -> String {
+        URL_SIGNER.mux_url(
+            &self.base_url,
+            video_id,
+            video_itag,
+            audio_itag,
+            self.client.as_deref(),
+        )
+    }
+
+    /// The URL clients should load a thumbnail from, routed through `/img` in privacy mode.
+    pub fn image_url(&self, url: &str) -> String {
+        let url = fix_thumbnail_url(url);
+        if !self.privacy {
+            return url;
+        }
+        imgproxy::proxy_url(&self.base_url, &url).unwrap_or(url)
+    }
+}
+
+fn parse_long(value: &Option<String>) -> Option<Long> {
+    value.as_ref()?.parse().ok().map(Long)
+}
+
+fn unix_millis(time: SystemTime) -> Option<Long> {
+    let since_epoch = time.duration_since(UNIX_EPOCH).ok()?;
+    Some(Long(since_epoch.as_millis() as i64))
+}
+
+/// Converts an extracted stream into a `StreamItem` with `proxyUrl` pointing at `/vid`.
+/// In privacy mode `url` points there too.
+fn stream_item(
+    stream: ExtractedStream,
+    kind: Kind,
+    context: &Context,
+    video_id: &str,
+) -> StreamItem {
+    let url = stream.url.unwrap_or_default();
+    let proxy_url = context.proxy_url(video_id, stream.itag as u32);
+    let codecs = codec_strings(&stream.mimeType);
+    let (video_codec, audio_codec) = match kind {
+        Kind::Muxed => (codecs.get(0), codecs.get(1)),
+        Kind::VideoOnly => (codecs.get(0), None),
+        Kind::AudioOnly => (None, codecs.get(0)),
+    };
+    let color_info = video_codec.and_then(|video_codec| color_info(video_codec));
+    let hdr = color_info.as_ref().map_or(false, ColorInfo::is_hdr)
+        || stream
+            .qualityLabel
+            .as_ref()
+            .map_or(false, |label| label.contains("HDR"));
+    // lastModified counts microseconds.
+    let last_modified_at = stream
+        .lastModified
+        .parse::<i64>()
+        .ok()
+        .map(|micros| Long(micros / 1000));
+    let upstream_expiry = url_expiry(&url);
+    let expires_at = if context.privacy {
+        // The proxied URL stops working when either its signature or the upstream URL
+        // it stands for runs out.
+        match (upstream_expiry, URL_SIGNER.expiry()) {
+            (Some(upstream), Some(signed)) => Some(upstream.min(signed)),
+            (upstream, signed) => upstream.or(signed),
+        }
+    } else {
+        upstream_expiry
+    };
+    StreamItem {
+        expiresAt: expires_at.and_then(unix_millis),
+        url: if context.privacy {
+            proxy_url.clone()
+        } else {
+            url
+        },
+        itag: stream.itag as i32,
+        kind,
+        durationMs: parse_long(&stream.approxDurationMs),
+        approxDurationMs: stream.approxDurationMs,
+        audioChannels: stream.audioChannels.map(|channels| channels as i32),
+        audioQuality: stream.audioQuality,
+        sampleRate: stream
+            .audioSampleRate
+            .as_ref()
+            .and_then(|rate| rate.parse().ok()),
+        audioSampleRate: stream.audioSampleRate,
+        averageBitrate: stream.averageBitrate.map(|bitrate| bitrate as i32),
+        bitrate: stream.bitrate as i32,
+        size: parse_long(&stream.contentLength),
+        contentLength: stream.contentLength,
+        height: stream.height.map(|height| height as i32),
+        width: stream.width.map(|width| width as i32),
+        fps: stream.qualityLabel.as_deref().and_then(label_fps),
+        hdr,
+        colorInfo: color_info,
+        quality: stream.quality,
+        qualityLabel: stream.qualityLabel,
+        lastModifiedAt: last_modified_at,
+        lastModified: stream.lastModified,
+        container: container(&stream.mimeType),
+        videoCodec: video_codec.map(|video_codec| codec(video_codec)),
+        audioCodec: audio_codec.map(|audio_codec| codec(audio_codec)),
+        mimeType: stream.mimeType,
+        proxyUrl: Some(proxy_url),
+    }
+}
+
+fn stream_items(
+    streams: Vec<ExtractedStream>,
+    kind: Kind,
+    context: &Context,
+    video_id: &str,
+) -> Vec<StreamItem> {
+    streams
+        .into_iter()
+        .map(|stream| stream_item(stream, kind, context, video_id))
+        .collect()
+}
+
+struct Video<D> {
+    video_id: String,
+    extractor: Arc<YTStreamExtractor<D>>,
+}
+
+impl<D> Video<D>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    /// The `StreamItem` of a stream picked by a format expression.
+    fn stream_item(
+        &self,
+        chosen: Option<&Candidate>,
+        context: &Context,
+    ) -> Result<Option<StreamItem>, FieldError> {
+        let chosen = match chosen {
+            Some(chosen) => chosen,
+            None => return Ok(None),
+        };
+        let streams = match chosen.kind {
+            Kind::Muxed => self.extractor.get_video_stream(),
+            Kind::VideoOnly => self.extractor.get_video_only_stream(),
+            Kind::AudioOnly => self.extractor.get_audio_streams(),
+        }
+        .map_err(field_error)?;
+        Ok(streams
+            .into_iter()
+            .find(|stream| stream.itag as u32 == chosen.stream.itag)
+            .map(|stream| stream_item(stream, chosen.kind, context, &self.video_id)))
+    }
 }
 
 #[juniper::graphql_object(Context = Context)]
-impl Video {
-    fn video_streams(&self) -> Result<Vec<StreamItem>, FieldError> {
-        let streams = self.extractor.get_video_stream()?;
-        let mut v = vec![];
-        for stream in streams {
-            let stream_str = serde_json::to_string(&stream)?;
-            v.push(serde_json::from_str(&stream_str)?);
+impl<D> Video<D>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    fn video_streams(&self, context: &Context) -> Result<Vec<StreamItem>, FieldError> {
+        let streams = self.extractor.get_video_stream().map_err(field_error)?;
+        Ok(stream_items(streams, Kind::Muxed, context, &self.video_id))
+    }
+    fn video_only_streams(&self, context: &Context) -> Result<Vec<StreamItem>, FieldError> {
+        let streams = self
+            .extractor
+            .get_video_only_stream()
+            .map_err(field_error)?;
+        Ok(stream_items(
+            streams,
+            Kind::VideoOnly,
+            context,
+            &self.video_id,
+        ))
+    }
+    fn audio_only_streams(&self, context: &Context) -> Result<Vec<StreamItem>, FieldError> {
+        let streams = self.extractor.get_audio_streams().map_err(field_error)?;
+        Ok(stream_items(
+            streams,
+            Kind::AudioOnly,
+            context,
+            &self.video_id,
+        ))
+    }
+
+    /// The streams a yt-dlp style format expression such as
+    /// `bestvideo[height<=1080][vcodec^=av01]/bestvideo+bestaudio[acodec=opus]` picks, or
+    /// null when no alternative matches.
+    fn select(
+        &self,
+        context: &Context,
+        format: String,
+    ) -> Result<Option<FormatSelection>, FieldError> {
+        let expr: FormatExpr = format.parse().map_err(bad_request)?;
+        let candidates = candidates(&self.extractor);
+        let (video, audio) = match expr.choose(&candidates) {
+            Some(chosen) => chosen,
+            None => return Ok(None),
+        };
+        Ok(Some(FormatSelection {
+            video: self.stream_item(video, context)?,
+            audio: self.stream_item(audio, context)?,
+        }))
+    }
+
+    /// Signed `/mux` URL serving the video-only stream `videoItag` and the audio stream
+    /// `audioItag` remuxed into one file as they download.
+    fn mux_url(
+        &self,
+        context: &Context,
+        video_itag: i32,
+        audio_itag: i32,
+    ) -> Result<String, FieldError> {
+        if video_itag < 0 || audio_itag < 0 {
+            return Err(bad_request("itags are not negative"));
         }
-        Ok(v)
-    }
-    fn video_only_streams(&self) -> Result<Vec<StreamItem>, FieldError> {
-        let streams = self.extractor.get_video_only_stream()?;
-        let mut v = vec![];
-        for stream in streams {
-            let stream_str = serde_json::to_string(&stream)?;
-            v.push(serde_json::from_str(&stream_str)?);
+        Ok(context.mux_url(&self.video_id, video_itag as u32, audio_itag as u32))
+    }
+
+    /// Signed `/vid` URL serving whichever stream a quality selector such as `best`,
+    /// `video-best`, `audio-best` or `720p` picks when it is requested, narrowed down to
+    /// streams at most `maxHeight` tall, of a `codec` and in a `container`.
+    fn selector_url(
+        &self,
+        context: &Context,
+        selector: String,
+        max_height: Option<i32>,
+        codec: Option<String>,
+        container: Option<String>,
+    ) -> Result<String, FieldError> {
+        let mut narrowing = HashMap::new();
+        if let Some(max_height) = max_height {
+            narrowing.insert("maxHeight".to_owned(), max_height.to_string());
+        }
+        if let Some(codec) = codec {
+            narrowing.insert("codec".to_owned(), codec);
         }
-        Ok(v)
-    }
-    fn audio_only_streams(&self) -> Result<Vec<StreamItem>, FieldError> {
-        let streams = self.extractor.get_audio_streams()?;
-        let mut v = vec![];
-        for stream in streams {
-            let stream_str = serde_json::to_string(&stream)?;
-            v.push(serde_json::from_str(&stream_str)?);
+        if let Some(container) = container {
+            narrowing.insert("container".to_owned(), container);
         }
-        Ok(v)
+        Selector::parse(&selector, &narrowing).map_err(bad_request)?;
+        Ok(context.selector_url(&self.video_id, &selector, &narrowing))
     }
 
     fn title(&self) -> Result<String, FieldError> {
-        Ok(self.extractor.get_name()?)
+        Ok(self.extractor.get_name().map_err(field_error)?)
     }
 
     fn description(&self) -> Result<String, FieldError> {
-        Ok(self.extractor.get_description(false)?.0)
+        Ok(self
+            .extractor
+            .get_description(false)
+            .map_err(field_error)?
+            .0)
     }
 
     fn uploader_name(&self) -> Result<String, FieldError> {
-        Ok(self.extractor.get_uploader_name()?)
+        Ok(self.extractor.get_uploader_name().map_err(field_error)?)
     }
 
     fn uploader_url(&self) -> Result<String, FieldError> {
-        Ok(self.extractor.get_uploader_url()?)
+        Ok(self.extractor.get_uploader_url().map_err(field_error)?)
     }
 
-    fn video_thumbnails(&self) -> Result<Vec<Thumbnail>, FieldError> {
-        let thumbs = self.extractor.get_video_thumbnails()?;
+    fn video_thumbnails(&self, context: &Context) -> Result<Vec<Thumbnail>, FieldError> {
+        let thumbs = self.extractor.get_video_thumbnails().map_err(field_error)?;
         let mut thumbf = vec![];
         for thumb in thumbs {
             thumbf.push(Thumbnail {
-                url: fix_thumbnail_url(&thumb.url),
+                url: context.image_url(&thumb.url),
                 height: thumb.height as i32,
                 width: thumb.width as i32,
             })
@@ -150,12 +409,15 @@ impl Video {
         Ok(thumbf)
     }
 
-    fn uploader_thumbnails(&self) -> Result<Vec<Thumbnail>, FieldError> {
-        let thumbs = self.extractor.get_uploader_avatar_url()?;
+    fn uploader_thumbnails(&self, context: &Context) -> Result<Vec<Thumbnail>, FieldError> {
+        let thumbs = self
+            .extractor
+            .get_uploader_avatar_url()
+            .map_err(field_error)?;
         let mut thumbf = vec![];
         for thumb in thumbs {
             thumbf.push(Thumbnail {
-                url: fix_thumbnail_url(&thumb.url),
+                url: context.image_url(&thumb.url),
                 height: thumb.height as i32,
                 width: thumb.width as i32,
             })
@@ -163,83 +425,144 @@ impl Video {
         Ok(thumbf)
     }
 
+    #[graphql(deprecation = "Use likesV2")]
     fn likes(&self) -> Result<i32, FieldError> {
-        Ok(self.extractor.get_like_count()? as i32)
+        Ok(self.likes_v2()?.saturating_i32())
     }
 
+    fn likes_v2(&self) -> Result<Long, FieldError> {
+        Ok(Long(
+            self.extractor.get_like_count().map_err(field_error)? as i64
+        ))
+    }
+
+    #[graphql(deprecation = "Use dislikesV2")]
     fn dislikes(&self) -> Result<i32, FieldError> {
-        Ok(self.extractor.get_dislike_count()? as i32)
+        Ok(self.dislikes_v2()?.saturating_i32())
+    }
+
+    fn dislikes_v2(&self) -> Result<Long, FieldError> {
+        Ok(Long(
+            self.extractor.get_dislike_count().map_err(field_error)? as i64,
+        ))
+    }
+
+    #[graphql(deprecation = "Use viewsV2")]
+    async fn views(&self) -> Result<i32, FieldError> {
+        Ok(self.views_v2().await?.saturating_i32())
     }
 
-    fn views(&self) -> Result<i32, FieldError> {
-        Ok(self.extractor.get_view_count()? as i32)
+    /// Read from the watch page's `videoDetails`, since rusty_pipe's getter wraps past 2^31.
+    async fn views_v2(&self) -> Result<Long, FieldError> {
+        let page = D::download(&format!(
+            "https://www.youtube.com/watch?v={}",
+            self.video_id
+        ))
+        .await;
+        if let Some(views) = page
+            .ok()
+            .and_then(|page| watch_page_count(&page, "viewCount"))
+        {
+            return Ok(views);
+        }
+        Ok(Long::from(
+            self.extractor.get_view_count().map_err(field_error)?,
+        ))
     }
 
+    #[graphql(deprecation = "Use lengthV2")]
     fn length(&self) -> Result<i32, FieldError> {
-        Ok(self.extractor.get_length()? as i32)
+        Ok(self.length_v2()?.saturating_i32())
+    }
+
+    /// Length in seconds.
+    fn length_v2(&self) -> Result<Long, FieldError> {
+        Ok(Long(
+            self.extractor.get_length().map_err(field_error)? as i64
+        ))
     }
 
-    fn related(&self) -> Result<Vec<SearchResult>, FieldError> {
+    fn related(&self, context: &Context) -> Result<Vec<SearchResult>, FieldError> {
         let mut result = vec![];
-        for item in self.extractor.get_related()? {
+        for item in self.extractor.get_related().map_err(field_error)? {
             result.push(match item {
-                YTSearchItem::StreamInfoItem(vid) => SearchResult::VideoInfo(VideoResult {
-                    name: vid.get_name()?,
-                    video_id: vid.video_id()?,
-                    is_ad: vid.is_ad().unwrap_or(false),
-                    is_premium_video: vid.is_premium_video().unwrap_or(false),
-                    url: vid.get_url()?,
-                    is_live: vid.is_live().unwrap_or(false),
-                    duration: vid.get_duration().ok(),
-                    uploader_name: vid.get_uploader_name().ok(),
-                    uploader_url: vid.get_uploader_url().ok(),
-                    upload_date: vid.get_textual_upload_date().ok(),
-                    view_count: vid.get_view_count().ok(),
-                    thumbnail: vid
-                        .get_thumbnails()?
-                        .iter()
-                        .map(|f| Thumbnail {
-                            url: fix_thumbnail_url(&f.url),
-                            width: f.width as i32,
-                            height: f.height as i32,
-                        })
-                        .collect(),
-                }),
+                YTSearchItem::StreamInfoItem(vid) => {
+                    let duration = vid.get_duration().ok();
+                    let view_count =
+                        count(&vid.video_info, &["viewCountText", "shortViewCountText"])
+                            .or_else(|| vid.get_view_count().ok().map(Long::from));
+                    SearchResult::VideoInfo(VideoResult {
+                        name: vid.get_name().map_err(field_error)?,
+                        video_id: vid.video_id().map_err(field_error)?,
+                        is_ad: vid.is_ad().unwrap_or(false),
+                        is_premium_video: vid.is_premium_video().unwrap_or(false),
+                        url: vid.get_url().map_err(field_error)?,
+                        is_live: vid.is_live().unwrap_or(false),
+                        duration,
+                        duration_v2: duration.map(Long::from),
+                        uploader_name: vid.get_uploader_name().ok(),
+                        uploader_url: vid.get_uploader_url().ok(),
+                        upload_date: vid.get_textual_upload_date().ok(),
+                        view_count: view_count.map(Long::saturating_i32),
+                        view_count_v2: view_count,
+                        thumbnail: vid
+                            .get_thumbnails()
+                            .map_err(field_error)?
+                            .iter()
+                            .map(|f| Thumbnail {
+                                url: context.image_url(&f.url),
+                                width: f.width as i32,
+                                height: f.height as i32,
+                            })
+                            .collect(),
+                    })
+                }
                 YTSearchItem::ChannelInfoItem(channel) => {
+                    let subscribers = count(&channel.channel_info, &["subscriberCountText"])
+                        .or_else(|| channel.get_subscriber_count().ok().map(Long::from));
+                    let videos = count(&channel.channel_info, &["videoCountText"])
+                        .or_else(|| channel.get_stream_count().ok().map(Long::from));
                     SearchResult::ChannelInfo(ChannelResult {
-                        name: channel.get_name()?,
-                        channel_id: channel.channel_id()?,
+                        name: channel.get_name().map_err(field_error)?,
+                        channel_id: channel.channel_id().map_err(field_error)?,
                         thumbnail: channel
-                            .get_thumbnails()?
+                            .get_thumbnails()
+                            .map_err(field_error)?
                             .iter()
                             .map(|f| Thumbnail {
-                                url: fix_thumbnail_url(&f.url),
+                                url: context.image_url(&f.url),
                                 width: f.width as i32,
                                 height: f.height as i32,
                             })
                             .collect(),
-                        url: channel.get_url()?,
-                        subscribers: channel.get_subscriber_count().ok(),
-                        videos: channel.get_stream_count().ok(),
-                        description: channel.get_description()?,
+                        url: channel.get_url().map_err(field_error)?,
+                        subscribers: subscribers.map(Long::saturating_i32),
+                        subscribers_v2: subscribers,
+                        videos: videos.map(Long::saturating_i32),
+                        videos_v2: videos,
+                        description: channel.get_description().map_err(field_error)?,
                     })
                 }
                 YTSearchItem::PlaylistInfoItem(playlist) => {
+                    let videos = count(&playlist.playlist_info, &["videoCount", "videoCountText"])
+                        .or_else(|| playlist.get_stream_count().ok().map(Long::from));
                     SearchResult::PlaylistInfo(PlaylistResult {
-                        name: playlist.get_name()?,
-                        playlist_id: playlist.playlist_id()?,
+                        name: playlist.get_name().map_err(field_error)?,
+                        playlist_id: playlist.playlist_id().map_err(field_error)?,
                         thumbnail: playlist
-                            .get_thumbnails()?
+                            .get_thumbnails()
+                            .map_err(field_error)?
                             .iter()
                             .map(|f| Thumbnail {
-                                url: fix_thumbnail_url(&f.url),
+                                url: context.image_url(&f.url),
                                 width: f.width as i32,
                                 height: f.height as i32,
                             })
                             .collect(),
-                        url: playlist.get_url()?,
+                        url: playlist.get_url().map_err(field_error)?,
                         uploader_name: playlist.get_uploader_name().ok(),
-                        videos: playlist.get_stream_count().ok(),
+                        videos: videos.map(Long::saturating_i32),
+                        videos_v2: videos,
                     })
                 }
             })
@@ -248,23 +571,55 @@ impl Video {
     }
 }
 
-#[derive(juniper::GraphQLObject, Serialize, Deserialize)]
+#[derive(juniper::GraphQLObject)]
 pub struct StreamItem {
     pub url: String,
     pub itag: i32,
+    pub kind: Kind,
+    #[graphql(deprecation = "Use durationMs")]
     pub approxDurationMs: Option<String>,
+    pub durationMs: Option<Long>,
     pub audioChannels: Option<i32>,
     pub audioQuality: Option<String>,
+    #[graphql(deprecation = "Use sampleRate")]
     pub audioSampleRate: Option<String>,
+    /// Audio sample rate in Hz.
+    pub sampleRate: Option<i32>,
     pub averageBitrate: Option<i32>,
     pub bitrate: i32,
+    #[graphql(deprecation = "Use size")]
     pub contentLength: Option<String>,
+    /// Length of the stream in bytes.
+    pub size: Option<Long>,
     pub height: Option<i32>,
     pub width: Option<i32>,
+    /// Frame rate read from the quality label, 30 when it only states a height.
+    pub fps: Option<i32>,
+    pub hdr: bool,
+    pub colorInfo: Option<ColorInfo>,
     pub quality: String,
     pub qualityLabel: Option<String>,
+    #[graphql(deprecation = "Use lastModifiedAt")]
     pub lastModified: String,
+    /// When the stream was last changed, in milliseconds since the Unix epoch.
+    pub lastModifiedAt: Option<Long>,
+    /// When `url` stops working, in milliseconds since the Unix epoch.
+    pub expiresAt: Option<Long>,
+    #[graphql(deprecation = "Use container, videoCodec and audioCodec")]
     pub mimeType: String,
+    pub container: Container,
+    pub videoCodec: Option<Codec>,
+    pub audioCodec: Option<Codec>,
+    /// Signed URL serving this stream through the `/vid` proxy.
+    pub proxyUrl: Option<String>,
+}
+
+/// The streams `Video.select` picked. `audio` is null when `video` carries sound or no
+/// audio was asked for, and `video` is null when only audio was.
+#[derive(juniper::GraphQLObject)]
+pub struct FormatSelection {
+    pub video: Option<StreamItem>,
+    pub audio: Option<StreamItem>,
 }
 
 #[derive(juniper::GraphQLObject, Serialize, Deserialize)]
@@ -274,46 +629,240 @@ pub struct Thumbnail {
     pub height: i32,
 }
 
-pub struct Query;
+pub type Extraction<T> = Result<Arc<T>, Arc<ParsingError>>;
+
+lazy_static! {
+    static ref EXTRACTIONS: Mutex<HashMap<TypeId, &'static (dyn Any + Send + Sync)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// In-flight extractions for downloader `D`; concurrent callers asking for the same
+/// video, search page, channel page or playlist page share one extraction. Each key
+/// starts with whether the extraction skips the page cache, so `noCache` callers only
+/// join extractions that skip it too.
+pub struct Extractions<D> {
+    video: SingleFlight<(bool, String), Extraction<YTStreamExtractor<D>>>,
+    search: SingleFlight<(bool, String, Option<String>), Extraction<YTSearchExtractor>>,
+    channel: SingleFlight<(bool, String, Option<String>), Extraction<YTChannelExtractor>>,
+    playlist: SingleFlight<(bool, String, Option<String>), Extraction<YTPlaylistExtractor>>,
+    trending: SingleFlight<bool, Extraction<YTTrendingExtractor>>,
+}
+
+impl<D> Extractions<D>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    pub fn get() -> &'static Self {
+        let mut registry = EXTRACTIONS.lock().unwrap();
+        let extractions = *registry.entry(TypeId::of::<D>()).or_insert_with(|| {
+            let extractions: &'static (dyn Any + Send + Sync) =
+                Box::leak(Box::new(Extractions::<D> {
+                    video: SingleFlight::new(),
+                    search: SingleFlight::new(),
+                    channel: SingleFlight::new(),
+                    playlist: SingleFlight::new(),
+                    trending: SingleFlight::new(),
+                }));
+            extractions
+        });
+        extractions.downcast_ref::<Self>().unwrap()
+    }
+
+    pub async fn video(&'static self, video_id: &str) -> Extraction<YTStreamExtractor<D>> {
+        CIRCUIT_BREAKER.check().map_err(Arc::new)?;
+        let id = video_id.to_owned();
+        let bypass = bypassed();
+        let handle = Handle::current();
+        let extract = async move {
+            let extractor = YTStreamExtractor::new(&id, D::default());
+            bypassing(bypass, proxy::for_video(&id, extractor)).await
+        };
+        // Deciphering waits for the JS runtimes synchronously, so the extraction is
+        // driven from a thread that may block.
+        let extraction = async move {
+            match tokio::task::spawn_blocking(move || handle.block_on(extract)).await {
+                Ok(extracted) => extracted.map(Arc::new).map_err(Arc::new),
+                Err(e) => Err(Arc::new(ParsingError::from(e.to_string()))),
+            }
+        };
+        self.video
+            .run((bypass, video_id.to_owned()), extraction)
+            .await
+    }
+
+    pub async fn search(
+        &'static self,
+        query: String,
+        page_url: Option<String>,
+    ) -> Extraction<YTSearchExtractor> {
+        CIRCUIT_BREAKER.check().map_err(Arc::new)?;
+        let bypass = bypassed();
+        let key = (bypass, query.clone(), page_url.clone());
+        let extraction = async move {
+            YTSearchExtractor::new::<D>(&query, page_url)
+                .await
+                .map(Arc::new)
+                .map_err(Arc::new)
+        };
+        self.search.run(key, bypassing(bypass, extraction)).await
+    }
+
+    pub async fn channel(
+        &'static self,
+        channel_id: String,
+        page_url: Option<String>,
+    ) -> Extraction<YTChannelExtractor> {
+        CIRCUIT_BREAKER.check().map_err(Arc::new)?;
+        let bypass = bypassed();
+        let key = (bypass, channel_id.clone(), page_url.clone());
+        let extraction = async move {
+            YTChannelExtractor::new::<D>(&channel_id, page_url)
+                .await
+                .map(Arc::new)
+                .map_err(Arc::new)
+        };
+        self.channel.run(key, bypassing(bypass, extraction)).await
+    }
+
+    pub async fn playlist(
+        &'static self,
+        playlist_id: String,
+        page_url: Option<String>,
+    ) -> Extraction<YTPlaylistExtractor> {
+        CIRCUIT_BREAKER.check().map_err(Arc::new)?;
+        let bypass = bypassed();
+        let key = (bypass, playlist_id.clone(), page_url.clone());
+        let extraction = async move {
+            YTPlaylistExtractor::new(&playlist_id, D::default(), page_url)
+                .await
+                .map(Arc::new)
+                .map_err(Arc::new)
+        };
+        self.playlist.run(key, bypassing(bypass, extraction)).await
+    }
+
+    pub async fn trending(&'static self) -> Extraction<YTTrendingExtractor> {
+        CIRCUIT_BREAKER.check().map_err(Arc::new)?;
+        let bypass = bypassed();
+        let extraction = async move {
+            YTTrendingExtractor::new(D::default())
+                .await
+                .map(Arc::new)
+                .map_err(Arc::new)
+        };
+        self.trending
+            .run(bypass, bypassing(bypass, extraction))
+            .await
+    }
+}
+
+pub struct Query<D> {
+    downloader: PhantomData<D>,
+}
+
+impl<D> Query<D> {
+    pub fn new() -> Self {
+        Query {
+            downloader: PhantomData,
+        }
+    }
+}
 
 #[juniper::graphql_object(Context = Context)]
-impl Query {
-    async fn video(video_id: String) -> Result<Video, FieldError> {
-        let ytextractor = YTStreamExtractor::new(&video_id, DownloaderObj).await?;
+impl<D> Query<D>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    async fn video(video_id: String, no_cache: Option<bool>) -> Result<Video<D>, FieldError> {
+        let extractor = bypassing(
+            no_cache.unwrap_or(false),
+            Extractions::<D>::get().video(&video_id),
+        )
+        .await
+        .map_err(field_error)?;
         Ok(Video {
-            extractor: ytextractor,
+            video_id,
+            extractor,
         })
     }
 
-    async fn search(query: String, page_url: Option<String>) -> Result<Search, FieldError> {
-        let extractor = YTSearchExtractor::new::<DownloaderObj>(&query, page_url).await?;
-        Ok(Search { extractor })
+    async fn search(
+        query: String,
+        page_url: Option<String>,
+        no_cache: Option<bool>,
+    ) -> Result<Search<D>, FieldError> {
+        let extractor = bypassing(
+            no_cache.unwrap_or(false),
+            Extractions::<D>::get().search(query, page_url),
+        )
+        .await
+        .map_err(field_error)?;
+        Ok(Search {
+            extractor,
+            downloader: PhantomData,
+        })
     }
 
-    async fn channel(channel_id: String, page_url: Option<String>) -> Result<Channel, FieldError> {
-        let extractor = YTChannelExtractor::new::<DownloaderObj>(&channel_id, page_url).await?;
+    async fn channel(
+        channel_id: String,
+        page_url: Option<String>,
+        no_cache: Option<bool>,
+    ) -> Result<Channel, FieldError> {
+        let extractor = bypassing(
+            no_cache.unwrap_or(false),
+            Extractions::<D>::get().channel(channel_id, page_url),
+        )
+        .await
+        .map_err(field_error)?;
         Ok(Channel { extractor })
     }
 
     async fn playlist(
         playlist_id: String,
         page_url: Option<String>,
+        no_cache: Option<bool>,
     ) -> Result<Playlist, FieldError> {
-        let extractor = YTPlaylistExtractor::new(&playlist_id, DownloaderObj, page_url).await?;
+        let extractor = bypassing(
+            no_cache.unwrap_or(false),
+            Extractions::<D>::get().playlist(playlist_id, page_url),
+        )
+        .await
+        .map_err(field_error)?;
         Ok(Playlist { extractor })
     }
 
-    async fn trending() -> Result<Trending, FieldError> {
-        let extractor = YTTrendingExtractor::new(DownloaderObj).await?;
+    async fn trending(no_cache: Option<bool>) -> Result<Trending, FieldError> {
+        let extractor = bypassing(
+            no_cache.unwrap_or(false),
+            Extractions::<D>::get().trending(),
+        )
+        .await
+        .map_err(field_error)?;
         Ok(Trending { extractor })
     }
+
+    fn cache_stats() -> CacheStats {
+        PAGE_CACHE.lock().unwrap().stats()
+    }
+
+    fn js_stats() -> JsStats {
+        JS_POOL.stats()
+    }
+
+    fn decipher_stats() -> DecipherStats {
+        DECIPHER_CACHE.lock().unwrap().stats()
+    }
 }
 
-type Schema = RootNode<'static, Query, EmptyMutation<Context>, EmptySubscription<Context>>;
+pub type Schema<D> =
+    RootNode<'static, Query<D>, EmptyMutation<Context>, EmptySubscription<Context>>;
 
-pub fn schema() -> Schema {
+pub fn schema<D>() -> Schema<D>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
     Schema::new(
-        Query,
+        Query::new(),
         EmptyMutation::<Context>::new(),
         EmptySubscription::<Context>::new(),
     )
@@ -0,0 +1,356 @@
+use crate::errors::{error_response, ErrorCode};
+use crate::httpclient::env_or;
+use crate::selector::{signed_stream, NARROWING_PARAMS};
+use hmac::{Hmac, Mac, NewMac};
+use lazy_static::lazy_static;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use warp::http::header::HeaderMap;
+use warp::http::Response;
+use warp::hyper::Body;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Mints and checks HMAC-signed `/vid` URLs. `VID_SIGNING_KEYS` is a comma separated
+/// list: the first key signs, every key verifies, so a new key can be put in front
+/// while URLs signed with the old one run out. Without keys every request is refused,
+/// unless `VID_ALLOW_UNSIGNED` deliberately leaves `/vid` open.
+pub struct UrlSigner {
+    keys: Vec<Vec<u8>>,
+    ttl: Duration,
+    bind_client: bool,
+    allow_unsigned: bool,
+}
+
+impl UrlSigner {
+    pub fn from_env() -> Self {
+        let keys: Vec<Vec<u8>> = std::env::var("VID_SIGNING_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|key| key.trim().as_bytes().to_vec())
+            .filter(|key| !key.is_empty())
+            .collect();
+        let allow_unsigned = env_or("VID_ALLOW_UNSIGNED", false);
+        match (keys.is_empty(), allow_unsigned) {
+            (true, true) => log::warn!("VID_ALLOW_UNSIGNED is set, /vid accepts unsigned requests"),
+            (true, false) => log::error!(
+                "VID_SIGNING_KEYS is not set, /vid refuses every request; set VID_ALLOW_UNSIGNED=true to serve unsigned ones"
+            ),
+            _ => {}
+        }
+        UrlSigner {
+            keys,
+            ttl: Duration::from_secs(env_or("VID_URL_TTL_SECS", 6 * 3600)),
+            bind_client: env_or("VID_SIGN_BIND_CLIENT", false),
+            allow_unsigned,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    fn mac(
+        &self,
+        key: &[u8],
+        video_id: &str,
+        stream: &str,
+        expires: u64,
+        client: Option<&str>,
+    ) -> HmacSha256 {
+        let client = if self.bind_client {
+            client.unwrap_or_default()
+        } else {
+            ""
+        };
+        let mut mac = HmacSha256::new_varkey(key).expect("hmac accepts any key length");
+        mac.update(format!("{}\n{}\n{}\n{}", video_id, stream, expires, client).as_bytes());
+        mac
+    }
+
+    /// The `exp` of a URL signed now, in seconds since the Unix epoch.
+    fn expires(&self) -> u64 {
+        (SystemTime::now() + self.ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    /// When a URL signed now stops being accepted, or `None` when URLs are not signed.
+    pub fn expiry(&self) -> Option<SystemTime> {
+        if self.enabled() {
+            Some(UNIX_EPOCH + Duration::from_secs(self.expires()))
+        } else {
+            None
+        }
+    }
+
+    /// The query string authorising `client` to fetch `stream` of `video_id`, an itag or a
+    /// selector such as `best`, until the TTL runs out.
+    pub fn sign(&self, video_id: &str, stream: &str, client: Option<&str>) -> String {
+        let key = match self.keys.first() {
+            Some(key) => key,
+            None => return String::new(),
+        };
+        let expires = self.expires();
+        let signature = self
+            .mac(key, video_id, stream, expires, client)
+            .finalize()
+            .into_bytes();
+        format!("?exp={}&sig={}", expires, hex::encode(signature))
+    }
+
+    /// The `/mux` URL combining two streams of `video_id`, signed for both itags.
+    pub fn mux_url(
+        &self,
+        base_url: &str,
+        video_id: &str,
+        video_itag: u32,
+        audio_itag: u32,
+        client: Option<&str>,
+    ) -> String {
+        let signature = self.sign(video_id, &mux_stream(video_itag, audio_itag), client);
+        format!(
+            "{}/mux/{}?video={}&audio={}{}",
+            base_url,
+            video_id,
+            video_itag,
+            audio_itag,
+            signature.replacen('?', "&", 1)
+        )
+    }
+
+    /// The `/vid` URL of a quality selector such as `best` or `720p`, narrowed down by the
+    /// `maxHeight`, `codec` and `container` values in `narrowing` and signed together
+    /// with them.
+    pub fn selector_url(
+        &self,
+        base_url: &str,
+        video_id: &str,
+        selector: &str,
+        narrowing: &HashMap<String, String>,
+        client: Option<&str>,
+    ) -> String {
+        let params: Vec<String> = NARROWING_PARAMS
+            .iter()
+            .filter_map(|name| {
+                let value = narrowing.get(*name)?;
+                Some(format!(
+                    "{}={}",
+                    name,
+                    utf8_percent_encode(value, NON_ALPHANUMERIC)
+                ))
+            })
+            .collect();
+        let signature = self.sign(video_id, &signed_stream(selector, narrowing), client);
+        let query = match (params.is_empty(), signature.is_empty()) {
+            (true, _) => signature,
+            (false, true) => format!("?{}", params.join("&")),
+            (false, false) => format!("?{}{}", params.join("&"), signature.replacen('?', "&", 1)),
+        };
+        format!("{}/vid/{}/{}{}", base_url, video_id, selector, query)
+    }
+
+    pub fn verify(
+        &self,
+        video_id: &str,
+        stream: &str,
+        query: &HashMap<String, String>,
+        client: Option<&str>,
+    ) -> Result<(), &'static str> {
+        if !self.enabled() {
+            return if self.allow_unsigned {
+                Ok(())
+            } else {
+                Err("url signing is not configured")
+            };
+        }
+        let expires: u64 = query
+            .get("exp")
+            .and_then(|exp| exp.parse().ok())
+            .ok_or("unsigned url")?;
+        let signature = query
+            .get("sig")
+            .and_then(|sig| hex::decode(sig).ok())
+            .ok_or("unsigned url")?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if expires < now {
+            return Err("expired url");
+        }
+        let valid = self.keys.iter().any(|key| {
+            self.mac(key, video_id, stream, expires, client)
+                .verify(&signature)
+                .is_ok()
+        });
+        if valid {
+            Ok(())
+        } else {
+            Err("invalid signature")
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref URL_SIGNER: UrlSigner = UrlSigner::from_env();
+    /// `TRUSTED_PROXIES`: addresses of the reverse proxies in front of the server, whose
+    /// `X-Forwarded-For` is believed.
+    static ref TRUSTED_PROXIES: Vec<IpAddr> = std::env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+}
+
+/// Identifies the requesting client by the peer address. When the peer is a trusted
+/// proxy, `X-Forwarded-For` is read from the right, past the trusted proxies' own hops;
+/// hops further left were written by the client and prove nothing.
+pub fn client_id(headers: &HeaderMap, remote: Option<SocketAddr>) -> Option<String> {
+    let peer = remote?.ip();
+    if !TRUSTED_PROXIES.contains(&peer) {
+        return Some(peer.to_string());
+    }
+    let hops: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|hop| hop.trim().parse().ok())
+        .collect();
+    let client = hops
+        .into_iter()
+        .rev()
+        .find(|hop| !TRUSTED_PROXIES.contains(hop))
+        .unwrap_or(peer);
+    Some(client.to_string())
+}
+
+/// Rejects `/vid` and live `/hls` requests without a valid, unexpired signature.
+pub fn authorize(
+    video_id: &str,
+    stream: &str,
+    query: &HashMap<String, String>,
+    remote: Option<SocketAddr>,
+    headers: &HeaderMap,
+) -> Result<(), Response<Body>> {
+    let client = client_id(headers, remote);
+    URL_SIGNER
+        .verify(video_id, stream, query, client.as_deref())
+        .map_err(|reason| {
+            log::warn!("refusing {} of {}: {}", stream, video_id, reason);
+            error_response(ErrorCode::Unauthorized, reason)
+        })
+}
+
+/// What a `/mux` URL is signed for: the video and audio itag together, so neither can be
+/// swapped for another stream.
+pub fn mux_stream(video_itag: u32, audio_itag: u32) -> String {
+    format!("{}+{}", video_itag, audio_itag)
+}
+
+/// The signed `/vid` URL for `itag` of `video_id`, relative unless `base_url` is known.
+pub fn proxy_url(base_url: &str, video_id: &str, itag: u32, client: Option<&str>) -> String {
+    format!(
+        "{}/vid/{}/{}{}",
+        base_url,
+        video_id,
+        itag,
+        URL_SIGNER.sign(video_id, &itag.to_string(), client)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> UrlSigner {
+        UrlSigner {
+            keys: vec![b"secret".to_vec()],
+            ttl: Duration::from_secs(60),
+            bind_client: true,
+            allow_unsigned: false,
+        }
+    }
+
+    fn query(url: &str) -> HashMap<String, String> {
+        url.splitn(2, '?')
+            .nth(1)
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| {
+                let mut pair = pair.splitn(2, '=');
+                Some((pair.next()?.to_owned(), pair.next()?.to_owned()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mux_urls_are_signed_for_both_streams() {
+        let signer = signer();
+        let url = signer.mux_url("http://localhost:8080", "abc", 137, 140, Some("10.0.0.1"));
+        assert!(url.starts_with("http://localhost:8080/mux/abc?video=137&audio=140&exp="));
+        let query = query(&url);
+        let client = Some("10.0.0.1");
+        assert!(signer
+            .verify("abc", &mux_stream(137, 140), &query, client)
+            .is_ok());
+        assert!(signer
+            .verify("abc", &mux_stream(137, 251), &query, client)
+            .is_err());
+        assert!(signer
+            .verify("xyz", &mux_stream(137, 140), &query, client)
+            .is_err());
+        assert!(signer
+            .verify("abc", &mux_stream(137, 140), &query, Some("10.0.0.2"))
+            .is_err());
+    }
+
+    #[test]
+    fn selector_urls_are_signed_with_their_narrowing_parameters() {
+        let signer = signer();
+        let narrowing: HashMap<String, String> = vec![
+            ("codec".to_owned(), "avc1".to_owned()),
+            ("maxHeight".to_owned(), "480".to_owned()),
+        ]
+        .into_iter()
+        .collect();
+        let url = signer.selector_url("", "abc", "720p", &narrowing, None);
+        assert!(url.starts_with("/vid/abc/720p?maxHeight=480&codec=avc1&exp="));
+        let mut query = query(&url);
+        assert!(signer
+            .verify("abc", &signed_stream("720p", &query), &query, None)
+            .is_ok());
+        assert!(signer
+            .verify("abc", &signed_stream("best", &query), &query, None)
+            .is_err());
+        query.insert("maxHeight".to_owned(), "1080".to_owned());
+        assert!(signer
+            .verify("abc", &signed_stream("720p", &query), &query, None)
+            .is_err());
+    }
+
+    #[test]
+    fn expiry_matches_the_signed_exp() {
+        let signer = signer();
+        let url = signer.selector_url("", "abc", "best", &HashMap::new(), None);
+        let exp: u64 = query(&url)["exp"].parse().unwrap();
+        let expiry = signer.expiry().unwrap().duration_since(UNIX_EPOCH).unwrap();
+        assert!(expiry.as_secs() >= exp && expiry.as_secs() <= exp + 1);
+        let unsigned = UrlSigner {
+            keys: vec![],
+            ..signer
+        };
+        assert!(unsigned.expiry().is_none());
+    }
+
+    #[test]
+    fn bare_selector_urls_carry_only_the_signature() {
+        let url = signer().selector_url("", "abc", "best", &HashMap::new(), None);
+        assert!(url.starts_with("/vid/abc/best?exp="));
+    }
+}
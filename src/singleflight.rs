@@ -0,0 +1,140 @@
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+type Flights<K, V> = Mutex<HashMap<K, Flight<V>>>;
+
+struct Flight<V> {
+    id: u64,
+    waiters: usize,
+    future: Shared<BoxFuture<'static, V>>,
+}
+
+/// Coalesces concurrent calls for the same key so only one of them does the work
+/// and every caller receives a clone of its output.
+pub struct SingleFlight<K, V> {
+    inflight: Flights<K, V>,
+    next_id: AtomicU64,
+}
+
+/// Forgets a flight once a caller has its output, while a panic in the flight unwinds
+/// through the caller, or when the last caller waiting on it is dropped, so the key is
+/// never left pointing at a finished or abandoned flight. A newer flight under the same
+/// key is left alone.
+struct Landing<'a, K: Hash + Eq, V> {
+    inflight: &'a Flights<K, V>,
+    key: K,
+    id: u64,
+    done: bool,
+}
+
+impl<'a, K: Hash + Eq, V> Drop for Landing<'a, K, V> {
+    fn drop(&mut self) {
+        let mut inflight = match self.inflight.lock() {
+            Ok(inflight) => inflight,
+            Err(_) => return,
+        };
+        let abandoned = match inflight.get_mut(&self.key) {
+            Some(flight) if flight.id == self.id => {
+                flight.waiters -= 1;
+                flight.waiters == 0
+            }
+            _ => return,
+        };
+        if self.done || abandoned || std::thread::panicking() {
+            inflight.remove(&self.key);
+        }
+    }
+}
+
+impl<K, V> SingleFlight<K, V>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        SingleFlight {
+            inflight: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn run<F>(&'static self, key: K, f: F) -> V
+    where
+        F: Future<Output = V> + Send + 'static,
+    {
+        let (id, flight) = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get_mut(&key) {
+                Some(flight) => {
+                    flight.waiters += 1;
+                    (flight.id, flight.future.clone())
+                }
+                None => {
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    let future = f.boxed().shared();
+                    inflight.insert(
+                        key.clone(),
+                        Flight {
+                            id,
+                            waiters: 1,
+                            future: future.clone(),
+                        },
+                    );
+                    (id, future)
+                }
+            }
+        };
+        let mut landing = Landing {
+            inflight: &self.inflight,
+            key,
+            id,
+            done: false,
+        };
+        let value = flight.await;
+        landing.done = true;
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SingleFlight;
+    use std::time::Duration;
+
+    fn flights() -> &'static SingleFlight<u32, u32> {
+        Box::leak(Box::new(SingleFlight::new()))
+    }
+
+    #[tokio::test]
+    async fn abandoned_flights_are_forgotten() {
+        let flights = flights();
+        let slow = async {
+            tokio::time::delay_for(Duration::from_secs(60)).await;
+            1
+        };
+        let abandoned = tokio::time::timeout(Duration::from_millis(10), flights.run(7, slow));
+        assert!(abandoned.await.is_err());
+        assert_eq!(flights.run(7, async { 2 }).await, 2);
+    }
+
+    #[tokio::test]
+    async fn followers_keep_a_flight_alive() {
+        let flights = flights();
+        let leader = flights.run(7, async {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            1
+        });
+        let leader = tokio::time::timeout(Duration::from_millis(10), leader);
+        let follower = async {
+            tokio::time::delay_for(Duration::from_millis(5)).await;
+            flights.run(7, async { 2 }).await
+        };
+        let (leader, follower) = futures::join!(leader, follower);
+        assert!(leader.is_err());
+        assert_eq!(follower, 1);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::cache::bypassing;
+use crate::httpclient::env_or;
+use crate::serverrunner::Extractions;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use rusty_pipe::downloader_trait::Downloader;
+use rusty_pipe::youtube_extractor::error::ParsingError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What `/vid` needs to know about a stream once it has been extracted.
+#[derive(Clone)]
+pub struct ResolvedStream {
+    pub url: String,
+    pub content_length: Option<String>,
+    pub mime_type: String,
+    pub last_modified: String,
+    expires: SystemTime,
+}
+
+/// When a googlevideo URL stops working, read from its `expire` query parameter.
+pub fn url_expiry(url: &str) -> Option<SystemTime> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let expire = url
+        .query_pairs()
+        .find(|(key, _)| key == "expire")?
+        .1
+        .parse::<u64>()
+        .ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(expire))
+}
+
+lazy_static! {
+    static ref STREAM_URLS: Mutex<LruCache<(String, u32), ResolvedStream>> =
+        Mutex::new(LruCache::new(env_or("STREAM_URL_CACHE_ENTRIES", 4096)));
+}
+
+fn cached(video_id: &str, itag: u32) -> Option<ResolvedStream> {
+    let mut urls = STREAM_URLS.lock().unwrap();
+    let key = (video_id.to_owned(), itag);
+    match urls.get(&key) {
+        Some(stream) if stream.expires > SystemTime::now() => Some(stream.clone()),
+        Some(_) => {
+            urls.pop(&key);
+            None
+        }
+        None => None,
+    }
+}
+
+/// Looks up the stream `itag` of `video_id`, extracting the video only when there is no
+/// unexpired URL cached for it. `fresh` forces a re-extraction that skips the page cache.
+/// Returns the stream and whether it came from the cache.
+pub async fn resolve_stream<D>(
+    video_id: &str,
+    itag: u32,
+    fresh: bool,
+) -> Result<Option<(ResolvedStream, bool)>, Arc<ParsingError>>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    if !fresh {
+        if let Some(stream) = cached(video_id, itag) {
+            return Ok(Some((stream, true)));
+        }
+    }
+    let videx = bypassing(fresh, Extractions::<D>::get().video(video_id)).await?;
+    let mut streams = videx.get_video_stream().unwrap_or_default();
+    streams.append(&mut videx.get_video_only_stream().unwrap_or_default());
+    streams.append(&mut videx.get_audio_streams().unwrap_or_default());
+
+    let margin = Duration::from_secs(env_or("STREAM_URL_EXPIRY_MARGIN_SECS", 60));
+    let fallback = Duration::from_secs(env_or("STREAM_URL_TTL_SECS", 300));
+    let mut found = None;
+    let mut urls = STREAM_URLS.lock().unwrap();
+    for stream in streams {
+        let url = match stream.url {
+            Some(url) => url,
+            None => continue,
+        };
+        let expires = url_expiry(&url)
+            .and_then(|expiry| expiry.checked_sub(margin))
+            .unwrap_or_else(|| SystemTime::now() + fallback);
+        let resolved = ResolvedStream {
+            url,
+            content_length: stream.contentLength,
+            mime_type: stream.mimeType,
+            last_modified: stream.lastModified,
+            expires,
+        };
+        if stream.itag == itag {
+            found = Some(resolved.clone());
+        }
+        urls.put((video_id.to_owned(), stream.itag), resolved);
+    }
+    Ok(found.map(|stream| (stream, false)))
+}
+
+/// Forgets the cached URL of a stream upstream has refused.
+pub fn invalidate(video_id: &str, itag: u32) {
+    STREAM_URLS
+        .lock()
+        .unwrap()
+        .pop(&(video_id.to_owned(), itag));
+}
@@ -0,0 +1,223 @@
+/// The file format a stream comes in.
+#[derive(juniper::GraphQLEnum, Clone, Copy, PartialEq, Debug)]
+pub enum Container {
+    Mp4,
+    Webm,
+    ThreeGpp,
+    Unknown,
+}
+
+#[derive(juniper::GraphQLEnum, Clone, Copy, PartialEq, Debug)]
+pub enum Codec {
+    H264,
+    Vp8,
+    Vp9,
+    Av1,
+    Aac,
+    Opus,
+    Vorbis,
+    Ac3,
+    Eac3,
+    Unknown,
+}
+
+#[derive(juniper::GraphQLEnum, Clone, Copy, PartialEq, Debug)]
+pub enum ColorPrimaries {
+    Bt709,
+    Bt2020,
+    Other,
+}
+
+#[derive(juniper::GraphQLEnum, Clone, Copy, PartialEq, Debug)]
+pub enum TransferFunction {
+    Sdr,
+    Pq,
+    Hlg,
+    Other,
+}
+
+/// Colour description carried in a `vp09` or `av01` codec string. Fields the codec
+/// string leaves out are null.
+#[derive(juniper::GraphQLObject, Clone, Debug)]
+pub struct ColorInfo {
+    pub bit_depth: Option<i32>,
+    pub primaries: Option<ColorPrimaries>,
+    pub transfer: Option<TransferFunction>,
+}
+
+impl ColorInfo {
+    /// PQ and HLG transfers are HDR, and so is BT.2020 colour whose transfer is unstated.
+    pub fn is_hdr(&self) -> bool {
+        match (self.transfer, self.primaries) {
+            (Some(TransferFunction::Pq), _) | (Some(TransferFunction::Hlg), _) => true,
+            (None, Some(ColorPrimaries::Bt2020)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Splits `video/mp4; codecs="avc1.640028"` into the container and codec list.
+pub fn split_mime_type(mime_type: &str) -> (&str, &str) {
+    let mut parts = mime_type.splitn(2, ';');
+    let container = parts.next().unwrap_or_default().trim();
+    let codecs = parts
+        .next()
+        .and_then(|params| params.trim().strip_prefix("codecs="))
+        .unwrap_or_default()
+        .trim_matches('"');
+    (container, codecs)
+}
+
+pub fn container(mime_type: &str) -> Container {
+    let (container, _) = split_mime_type(mime_type);
+    match container.rsplit('/').next().unwrap_or_default() {
+        "mp4" => Container::Mp4,
+        "webm" => Container::Webm,
+        "3gpp" => Container::ThreeGpp,
+        _ => Container::Unknown,
+    }
+}
+
+/// The codec strings of a mime type, such as `vp9` or `mp4a.40.2`.
+pub fn codec_strings(mime_type: &str) -> Vec<&str> {
+    split_mime_type(mime_type)
+        .1
+        .split(',')
+        .map(str::trim)
+        .filter(|codec| !codec.is_empty())
+        .collect()
+}
+
+pub fn codec(codec: &str) -> Codec {
+    let family = codec.split('.').next().unwrap_or_default();
+    match family.to_lowercase().as_str() {
+        "avc1" | "avc3" => Codec::H264,
+        "vp8" => Codec::Vp8,
+        "vp9" | "vp09" => Codec::Vp9,
+        "av01" => Codec::Av1,
+        "mp4a" => Codec::Aac,
+        "opus" => Codec::Opus,
+        "vorbis" => Codec::Vorbis,
+        "ac-3" => Codec::Ac3,
+        "ec-3" => Codec::Eac3,
+        _ => Codec::Unknown,
+    }
+}
+
+fn primaries(code: &str) -> Option<ColorPrimaries> {
+    Some(match code.parse::<u32>().ok()? {
+        1 => ColorPrimaries::Bt709,
+        9 => ColorPrimaries::Bt2020,
+        _ => ColorPrimaries::Other,
+    })
+}
+
+fn transfer(code: &str) -> Option<TransferFunction> {
+    Some(match code.parse::<u32>().ok()? {
+        1 | 6 | 13 | 14 | 15 => TransferFunction::Sdr,
+        16 => TransferFunction::Pq,
+        18 => TransferFunction::Hlg,
+        _ => TransferFunction::Other,
+    })
+}
+
+/// Reads the bit depth and colour code points from `vp09.PP.LL.DD.CC.cp.tc.mc` or
+/// `av01.P.LLT.DD.M.CCC.cp.tc.mc`. YouTube's older `vp9.2` means 10-bit VP9 profile 2,
+/// which it only serves for HDR.
+pub fn color_info(codec: &str) -> Option<ColorInfo> {
+    let parts: Vec<&str> = codec.split('.').collect();
+    let (depth, colour) = match parts[0].to_lowercase().as_str() {
+        "vp09" => (parts.get(3), parts.get(5..8)),
+        "av01" => (parts.get(3), parts.get(6..9)),
+        "vp9" if parts.get(1) == Some(&"2") => {
+            return Some(ColorInfo {
+                bit_depth: Some(10),
+                primaries: Some(ColorPrimaries::Bt2020),
+                transfer: None,
+            })
+        }
+        _ => return None,
+    };
+    Some(ColorInfo {
+        bit_depth: depth.and_then(|depth| depth.parse().ok()),
+        primaries: colour.and_then(|colour| primaries(colour[0])),
+        transfer: colour.and_then(|colour| transfer(colour[1])),
+    })
+}
+
+/// The frame rate a quality label such as `1080p60` states. YouTube only labels frame
+/// rates above 30, so a label with a height and no frame rate, such as `720p`, means 30.
+pub fn label_fps(quality_label: &str) -> Option<i32> {
+    let label = quality_label.split_whitespace().next()?;
+    let (height, fps) = label.split_at(label.find('p')? + 1);
+    height.trim_end_matches('p').parse::<u32>().ok()?;
+    if fps.is_empty() {
+        Some(30)
+    } else {
+        fps.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vp09_color_info() {
+        let info = color_info("vp09.02.51.10.01.09.16.09.00").unwrap();
+        assert_eq!(info.bit_depth, Some(10));
+        assert_eq!(info.primaries, Some(ColorPrimaries::Bt2020));
+        assert_eq!(info.transfer, Some(TransferFunction::Pq));
+        assert!(info.is_hdr());
+        assert_eq!(codec("vp09.02.51.10.01.09.16.09.00"), Codec::Vp9);
+
+        let sdr = color_info("vp09.00.40.08.01.01.01.01.00").unwrap();
+        assert_eq!(sdr.bit_depth, Some(8));
+        assert!(!sdr.is_hdr());
+        let short = color_info("vp09.00.40.08").unwrap();
+        assert_eq!(short.bit_depth, Some(8));
+        assert_eq!(short.primaries, None);
+    }
+
+    #[test]
+    fn av01_color_info() {
+        let info = color_info("av01.0.13M.10.0.110.09.18.09.0").unwrap();
+        assert_eq!(info.bit_depth, Some(10));
+        assert_eq!(info.primaries, Some(ColorPrimaries::Bt2020));
+        assert_eq!(info.transfer, Some(TransferFunction::Hlg));
+        assert!(info.is_hdr());
+        assert_eq!(color_info("av01.0.08M.08").unwrap().bit_depth, Some(8));
+        assert_eq!(codec("av01.0.08M.08"), Codec::Av1);
+    }
+
+    #[test]
+    fn short_vp9_strings() {
+        let info = color_info("vp9.2").unwrap();
+        assert_eq!(info.bit_depth, Some(10));
+        assert_eq!(info.transfer, None);
+        assert!(info.is_hdr());
+        assert!(color_info("vp9").is_none());
+        assert_eq!(codec("vp9.2"), Codec::Vp9);
+    }
+
+    #[test]
+    fn audio_codec_strings() {
+        let mime_type = r#"video/mp4; codecs="avc1.42001E, mp4a.40.2""#;
+        assert_eq!(codec_strings(mime_type), vec!["avc1.42001E", "mp4a.40.2"]);
+        assert_eq!(container(mime_type), Container::Mp4);
+        assert_eq!(codec("mp4a.40.2"), Codec::Aac);
+        assert_eq!(codec("avc1.42001E"), Codec::H264);
+        assert!(color_info("mp4a.40.2").is_none());
+        assert_eq!(codec("opus"), Codec::Opus);
+    }
+
+    #[test]
+    fn fps_from_quality_labels() {
+        assert_eq!(label_fps("1080p60"), Some(60));
+        assert_eq!(label_fps("2160p60 HDR"), Some(60));
+        assert_eq!(label_fps("720p"), Some(30));
+        assert_eq!(label_fps("1080p HDR"), Some(30));
+        assert_eq!(label_fps("tiny"), None);
+        assert_eq!(label_fps(""), None);
+    }
+}
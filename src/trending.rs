@@ -1,37 +1,45 @@
+use crate::counts::count;
+use crate::errors::field_error;
+use crate::scalars::Long;
 use crate::search::VideoResult;
 use crate::Thumbnail;
-use rusty_pipe::utils::utils::fix_thumbnail_url;
 use rusty_pipe::youtube_extractor::error::ParsingError;
 use rusty_pipe::youtube_extractor::trending_extractor::YTTrendingExtractor;
 use crate::Context;
 use juniper::FieldError;
+use std::sync::Arc;
 
 pub struct Trending {
-    pub extractor: YTTrendingExtractor,
+    pub extractor: Arc<YTTrendingExtractor>,
 }
 
 #[juniper::graphql_object(Context = Context)]
 impl Trending {
-    fn videos(&self) -> Result<Vec<VideoResult>, FieldError> {
+    fn videos(&self, context: &Context) -> Result<Vec<VideoResult>, FieldError> {
         let mut videos = vec![];
-        for vid in self.extractor.get_videos()? {
+        for vid in self.extractor.get_videos().map_err(field_error)? {
+            let duration = vid.get_duration().ok();
+            let view_count = count(&vid.video_info, &["viewCountText", "shortViewCountText"])
+                .or_else(|| vid.get_view_count().ok().map(Long::from));
             videos.push(VideoResult {
-                name: vid.get_name()?,
-                video_id: vid.video_id()?,
+                name: vid.get_name().map_err(field_error)?,
+                video_id: vid.video_id().map_err(field_error)?,
                 is_ad: vid.is_ad().unwrap_or(false),
                 is_premium_video: vid.is_premium_video().unwrap_or(false),
-                url: vid.get_url()?,
+                url: vid.get_url().map_err(field_error)?,
                 is_live: vid.is_live().unwrap_or(false),
-                duration: vid.get_duration().ok(),
+                duration,
+                duration_v2: duration.map(Long::from),
                 uploader_name: vid.get_uploader_name().ok(),
                 uploader_url: vid.get_uploader_url().ok(),
                 upload_date: vid.get_textual_upload_date().ok(),
-                view_count: vid.get_view_count().ok(),
+                view_count: view_count.map(Long::saturating_i32),
+                view_count_v2: view_count,
                 thumbnail: vid
-                    .get_thumbnails()?
+                    .get_thumbnails().map_err(field_error)?
                     .iter()
                     .map(|f| Thumbnail {
-                        url: fix_thumbnail_url(&f.url),
+                        url: context.image_url(&f.url),
                         width: f.width as i32,
                         height: f.height as i32,
                     })
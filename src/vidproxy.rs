@@ -1,45 +1,382 @@
-use crate::DownloaderObj;
-use rusty_pipe::youtube_extractor::error::ParsingError;
-use rusty_pipe::youtube_extractor::stream_extractor::YTStreamExtractor;
-use warp::Filter;
-
-
-pub async fn vidproxyhandle(video_id: String, itag: u32) -> Result<impl warp::Reply, warp::Rejection> {
-    let videx = YTStreamExtractor::new(&video_id, DownloaderObj)
-        .await
-        .map_err(|e| {eprintln!("stream not created");warp::reject::not_found()})?;
-    let mut streams = videx.get_video_only_stream().unwrap_or_default();
-    streams.append(&mut videx.get_audio_streams().unwrap_or_default());
-    let reqstream = streams.iter().find(|st| st.itag == itag);
-    match reqstream {
-        Some(reqstream) => {
-            use hyper_tls::HttpsConnector;
-
-            // use warp::hyper::Client;
-            let https = HttpsConnector::new();
-            let http_client =  hyper::Client::builder().build::<_, warp::hyper::Body>(https);
-            use std::str::FromStr;
-            use warp::hyper::Uri;
-            
-            println!("Serving url {:#?}",reqstream.url);
-            let resp = http_client
-                .get(Uri::from_str(reqstream.url.as_ref().unwrap_or(&"".to_string())).unwrap())
-                .await
-                .map_err(|e| {eprintln!("http error {:#}",e);warp::reject::not_found()})?;
-            println!("Success {:#?}",resp);
-            
-            let location = resp.headers().get(hyper::header::LOCATION);
-            if let Some(location)= location{
-                println!("following redirect {:#?}",location);
-                let resp = http_client
-                .get(Uri::from_str(location.to_str().unwrap()).unwrap())
-                .await
-                .map_err(|e| {eprintln!("http error {:#}",e);warp::reject::not_found()})?;
-                println!("Success {:#?}",resp);
-                return Ok(resp)
+use crate::chunked;
+use crate::errors::{error_response, parsing_error_response, ErrorCode};
+use crate::httpclient::env_or;
+use crate::proxy::PROXY_POOL;
+use crate::selector::{select, signed_stream};
+use crate::signing::authorize;
+use crate::streamcache::{invalidate, resolve_stream, ResolvedStream};
+use rusty_pipe::downloader_trait::Downloader;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use warp::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use warp::http::{Method, Response, StatusCode};
+use warp::hyper::Body;
+
+/// Client request headers passed upstream so seeking and revalidation work end to end.
+pub(crate) const FORWARDED_REQUEST_HEADERS: [HeaderName; 6] = [
+    header::RANGE,
+    header::IF_RANGE,
+    header::IF_MATCH,
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::IF_UNMODIFIED_SINCE,
+];
+
+pub async fn vidproxyhandle<D>(
+    video_id: String,
+    stream: String,
+    query: HashMap<String, String>,
+    remote: Option<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, warp::Rejection>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    let itag = match authorized_itag::<D>(&video_id, &stream, &query, remote, &headers).await {
+        Ok(itag) => itag,
+        Err(resp) => return Ok(resp),
+    };
+    let mut fresh = false;
+    loop {
+        let (reqstream, cached) = match resolve::<D>(&video_id, itag, fresh).await {
+            Ok(resolved) => resolved,
+            Err(resp) => return Ok(resp),
+        };
+        let etag = etag(&video_id, itag, &reqstream);
+        let forwarded = match preconditions(&headers, &etag, last_modified(&reqstream)) {
+            Ok(forwarded) => forwarded,
+            Err(resp) => return Ok(resp),
+        };
+        let proxy = PROXY_POOL.for_stream(&video_id);
+        log::debug!(
+            "Serving url {:#?} via {}",
+            reqstream.url,
+            PROXY_POOL.get(proxy).url
+        );
+        let plan = chunked::plan(&forwarded, reqstream.content_length.as_deref());
+        let request_headers = match &plan {
+            Some(plan) => plan.chunk_headers(&forwarded, plan.first_chunk()),
+            None => forwarded.clone(),
+        };
+        let resp = match fetch(Method::GET, proxy, &reqstream.url, &request_headers).await {
+            Ok(resp) => resp,
+            Err(resp) => return Ok(resp),
+        };
+        log::debug!("Success {:#?}", resp);
+
+        if cached && (resp.status() == StatusCode::FORBIDDEN || resp.status() == StatusCode::GONE) {
+            log::debug!("stale url for {} itag {}, extracting again", video_id, itag);
+            invalidate(&video_id, itag);
+            fresh = true;
+            continue;
+        }
+        let response = match plan {
+            Some(plan) if resp.status() == StatusCode::PARTIAL_CONTENT => {
+                chunked::respond(resp, proxy, &forwarded, plan, &reqstream.mime_type)
+            }
+            _ => relay(resp, reqstream.content_length.as_deref()),
+        };
+        return Ok(tagged(response, &etag));
+    }
+}
+
+/// Answers `HEAD` from the extracted stream metadata, only asking upstream when the
+/// extractor did not report a content length.
+pub async fn vidheadhandle<D>(
+    video_id: String,
+    stream: String,
+    query: HashMap<String, String>,
+    remote: Option<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, warp::Rejection>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    let itag = match authorized_itag::<D>(&video_id, &stream, &query, remote, &headers).await {
+        Ok(itag) => itag,
+        Err(resp) => return Ok(resp),
+    };
+    let (reqstream, _) = match resolve::<D>(&video_id, itag, false).await {
+        Ok(resolved) => resolved,
+        Err(resp) => return Ok(resp),
+    };
+    let etag = etag(&video_id, itag, &reqstream);
+    let forwarded = match preconditions(&headers, &etag, last_modified(&reqstream)) {
+        Ok(forwarded) => forwarded,
+        Err(resp) => return Ok(resp),
+    };
+    let length = match &reqstream.content_length {
+        Some(length) => length,
+        None => {
+            let proxy = PROXY_POOL.for_stream(&video_id);
+            return Ok(
+                match fetch(Method::HEAD, proxy, &reqstream.url, &forwarded).await {
+                    Ok(resp) => tagged(relay(resp, reqstream.content_length.as_deref()), &etag),
+                    Err(resp) => resp,
+                },
+            );
+        }
+    };
+
+    let mut builder = Response::builder()
+        .header(header::CONTENT_TYPE, reqstream.mime_type.as_str())
+        .header(header::CONTENT_LENGTH, length.as_str())
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, etag.as_str());
+    if let Some(modified) = last_modified(&reqstream) {
+        builder = builder.header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+    Ok(builder
+        .body(Body::empty())
+        .unwrap_or_else(|e| error_response(ErrorCode::UpstreamError, e)))
+}
+
+/// When the stream last changed, from the `lm` microseconds upstream puts in its URL,
+/// to the second as HTTP dates carry it.
+fn last_modified(stream: &ResolvedStream) -> Option<SystemTime> {
+    let micros = stream.last_modified.parse::<u64>().ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(micros / 1_000_000))
+}
+
+/// The entity tag `/vid` gives a stream. Upstream knows nothing of it, so requests
+/// conditional on it are answered by `preconditions` rather than forwarded.
+fn etag(video_id: &str, itag: u32, stream: &ResolvedStream) -> String {
+    format!("\"{}-{}-{}\"", video_id, itag, stream.last_modified)
+}
+
+/// Whether an `If-Match` or `If-None-Match` list names `etag`, or `None` if absent.
+fn names(value: Option<&HeaderValue>, etag: &str) -> Option<bool> {
+    let value = value?.to_str().ok()?;
+    Some(value.split(',').any(|tag| {
+        let tag = tag.trim();
+        tag == "*" || tag.trim_start_matches("W/") == etag
+    }))
+}
+
+/// Evaluates the client's entity tag conditions against `etag`, returning the headers
+/// to send upstream without them, or the 304 or 412 response to send instead. A Range
+/// whose `If-Range` matches neither `etag` nor, for a date, `modified` is dropped, so
+/// the whole stream is sent.
+fn preconditions(
+    headers: &HeaderMap,
+    etag: &str,
+    modified: Option<SystemTime>,
+) -> Result<HeaderMap, Response<Body>> {
+    let status = if names(headers.get(header::IF_MATCH), etag) == Some(false) {
+        Some(StatusCode::PRECONDITION_FAILED)
+    } else if names(headers.get(header::IF_NONE_MATCH), etag) == Some(true) {
+        Some(StatusCode::NOT_MODIFIED)
+    } else {
+        None
+    };
+    if let Some(status) = status {
+        let resp = Response::builder()
+            .status(status)
+            .header(header::ETAG, etag)
+            .body(Body::empty())
+            .unwrap_or_else(|e| error_response(ErrorCode::UpstreamError, e));
+        return Err(resp);
+    }
+    let mut forwarded = headers.clone();
+    forwarded.remove(header::IF_MATCH);
+    forwarded.remove(header::IF_NONE_MATCH);
+    if let Some(if_range) = forwarded.remove(header::IF_RANGE) {
+        let matches = match if_range.to_str() {
+            Ok(tag) if tag.starts_with('"') || tag.starts_with("W/") => tag == etag,
+            Ok(date) => match (httpdate::parse_http_date(date), modified) {
+                (Ok(date), Some(modified)) => date == modified,
+                _ => false,
+            },
+            Err(_) => false,
+        };
+        if !matches {
+            forwarded.remove(header::RANGE);
+        }
+    }
+    Ok(forwarded)
+}
+
+/// Sets `etag` on a successful response in place of any upstream one.
+fn tagged(mut resp: Response<Body>, etag: &str) -> Response<Body> {
+    if resp.status().is_success() {
+        if let Ok(value) = HeaderValue::from_str(etag) {
+            resp.headers_mut().insert(header::ETAG, value);
+        }
+    }
+    resp
+}
+
+/// Checks the signature over the stream segment of a `/vid` URL and any selector
+/// parameters, then resolves it to an itag: either the itag itself or a quality
+/// selector such as `best` or `720p`.
+async fn authorized_itag<D>(
+    video_id: &str,
+    stream: &str,
+    query: &HashMap<String, String>,
+    remote: Option<SocketAddr>,
+    headers: &HeaderMap,
+) -> Result<u32, Response<Body>>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    authorize(
+        video_id,
+        &signed_stream(stream, query),
+        query,
+        remote,
+        headers,
+    )?;
+    match stream.parse::<u32>() {
+        Ok(itag) => Ok(itag),
+        Err(_) => select::<D>(video_id, stream, query).await,
+    }
+}
+
+/// Resolves the stream `/vid` was asked for, or the error response to send instead.
+pub(crate) async fn resolve<D>(
+    video_id: &str,
+    itag: u32,
+    fresh: bool,
+) -> Result<(ResolvedStream, bool), Response<Body>>
+where
+    D: Downloader + Default + Send + Sync + 'static,
+{
+    match resolve_stream::<D>(video_id, itag, fresh).await {
+        Ok(Some(resolved)) => Ok(resolved),
+        Ok(None) => {
+            log::warn!("No stream of itag {}", itag);
+            Err(error_response(
+                ErrorCode::NotFound,
+                format!("no stream of itag {} for {}", itag, video_id),
+            ))
+        }
+        Err(e) => {
+            log::warn!("stream not created {}", e);
+            Err(parsing_error_response(e))
+        }
+    }
+}
+
+/// Requests `url` through `proxy`'s media client, following at most `VID_MAX_REDIRECTS`
+/// redirects. Failures come back as ready-to-send 502 responses.
+pub(crate) async fn fetch(
+    method: Method,
+    proxy: usize,
+    url: &str,
+    headers: &HeaderMap,
+) -> Result<reqwest::Response, Response<Body>> {
+    let max_redirects = env_or("VID_MAX_REDIRECTS", 5);
+    let mut url = reqwest::Url::parse(url).map_err(|e| {
+        log::warn!("malformed upstream url {:#?}: {}", url, e);
+        error_response(ErrorCode::UpstreamError, "malformed upstream url")
+    })?;
+    for _ in 0..=max_redirects {
+        let mut request = PROXY_POOL
+            .get(proxy)
+            .media_client
+            .request(method.clone(), url.clone());
+        for name in FORWARDED_REQUEST_HEADERS.iter() {
+            if let Some(value) = headers.get(name) {
+                request = request.header(name, value);
+            }
+        }
+        let resp = request.send().await.map_err(|e| {
+            log::warn!("http error {:#}", e);
+            PROXY_POOL.report_failure(proxy);
+            error_response(ErrorCode::UpstreamError, e)
+        })?;
+        if !follows(resp.status()) {
+            return Ok(resp);
+        }
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| {
+                error_response(ErrorCode::UpstreamError, "redirect without a location")
+            })?;
+        url = url.join(location).map_err(|e| {
+            log::warn!("malformed redirect {:#?}: {}", location, e);
+            error_response(ErrorCode::UpstreamError, "malformed redirect location")
+        })?;
+        log::debug!("following redirect {}", url);
+    }
+    Err(error_response(
+        ErrorCode::UpstreamError,
+        format!("more than {} redirects", max_redirects),
+    ))
+}
+
+/// Whether `fetch` follows a response with `status`. Other 3xx answers, such as
+/// 304 Not Modified, are relayed as they are.
+fn follows(status: StatusCode) -> bool {
+    match status {
+        StatusCode::MOVED_PERMANENTLY
+        | StatusCode::FOUND
+        | StatusCode::SEE_OTHER
+        | StatusCode::TEMPORARY_REDIRECT
+        | StatusCode::PERMANENT_REDIRECT => true,
+        _ => false,
+    }
+}
+
+/// Whether an upstream response header may be relayed to the client. Hop-by-hop
+/// headers describe the upstream connection only, and cookies, reporting endpoints and
+/// Google's internal headers would leak upstream details to clients.
+pub(crate) fn relayable(name: &HeaderName, connection_tokens: &[String]) -> bool {
+    let name = name.as_str();
+    let hop_by_hop = [
+        "connection",
+        "keep-alive",
+        "proxy-authenticate",
+        "proxy-authorization",
+        "te",
+        "trailer",
+        "transfer-encoding",
+        "upgrade",
+    ];
+    let tracking = ["set-cookie", "alt-svc", "report-to", "nel", "server", "p3p"];
+    !hop_by_hop.contains(&name)
+        && !tracking.contains(&name)
+        && !name.starts_with("x-")
+        && !connection_tokens.iter().any(|token| token == name)
+}
+
+/// Headers named in `Connection`, which are hop-by-hop too.
+pub(crate) fn connection_tokens(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_lowercase())
+        .collect()
+}
+
+/// Streams an upstream media response to the client. `content_length` is the full size
+/// of the stream, if known, for answering unsatisfiable ranges.
+pub(crate) fn relay(resp: reqwest::Response, content_length: Option<&str>) -> Response<Body> {
+    let connection_tokens = connection_tokens(resp.headers());
+    let mut builder = Response::builder().status(resp.status());
+    for (name, value) in resp.headers() {
+        if relayable(name, &connection_tokens) {
+            builder = builder.header(name, value);
+        }
+    }
+    if !resp.headers().contains_key(header::ACCEPT_RANGES) {
+        builder = builder.header(header::ACCEPT_RANGES, "bytes");
+    }
+    if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE
+        && !resp.headers().contains_key(header::CONTENT_RANGE)
+    {
+        if let Some(length) = content_length {
+            if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", length)) {
+                builder = builder.header(header::CONTENT_RANGE, value);
             }
-            Ok(resp)
         }
-        None =>{eprintln!("No stream of itag {}",itag); Err(warp::reject::not_found())},
     }
+    builder
+        .body(Body::wrap_stream(resp.bytes_stream()))
+        .unwrap_or_else(|e| error_response(ErrorCode::UpstreamError, e))
 }
@@ -0,0 +1,325 @@
+use crate::mux::{Output, Reader};
+use std::ops::Range;
+
+const EBML: u64 = 0x1A45_DFA3;
+const SEGMENT: u64 = 0x1853_8067;
+const INFO: u64 = 0x1549_A966;
+const TIMECODE_SCALE: u64 = 0x2A_D7B1;
+const TRACKS: u64 = 0x1654_AE6B;
+const TRACK_ENTRY: u64 = 0xAE;
+const TRACK_NUMBER: u64 = 0xD7;
+const TRACK_UID: u64 = 0x73C5;
+const CLUSTER: u64 = 0x1F43_B675;
+const CLUSTER_TIMECODE: u64 = 0xE7;
+const SIMPLE_BLOCK: u64 = 0xA3;
+const BLOCK_GROUP: u64 = 0xA0;
+const BLOCK: u64 = 0xA1;
+
+/// A Segment header of unknown size, so the muxed file can be written as it goes.
+const UNSIZED_SEGMENT: [u8; 12] = [
+    0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+/// Reads an EBML variable length integer, keeping the length marker for element ids.
+/// Returns the value and its length, or a `None` value for the reserved unknown size.
+fn vint(data: &[u8], pos: usize, marker: bool) -> Option<(Option<u64>, usize)> {
+    let first = *data.get(pos)?;
+    if first == 0 {
+        return None;
+    }
+    let len = first.leading_zeros() as usize + 1;
+    let mut value = if marker {
+        first as u64
+    } else {
+        first as u64 & (0xFF >> len)
+    };
+    for i in 1..len {
+        value = (value << 8) | *data.get(pos + i)? as u64;
+    }
+    let unknown = !marker && value == (1u64 << (7 * len)) - 1;
+    Some((if unknown { None } else { Some(value) }, len))
+}
+
+struct Header {
+    id: u64,
+    size: Option<u64>,
+    len: usize,
+}
+
+fn parse_header(data: &[u8], pos: usize) -> Option<Header> {
+    let (id, id_len) = vint(data, pos, true)?;
+    let (size, size_len) = vint(data, pos + id_len, false)?;
+    Some(Header {
+        id: id?,
+        size,
+        len: id_len + size_len,
+    })
+}
+
+/// The sized elements laid out back to back in `data[within]`, as their id, whole range
+/// and payload range.
+fn elements(data: &[u8], within: Range<usize>) -> Vec<(u64, Range<usize>, Range<usize>)> {
+    let mut found = vec![];
+    let mut pos = within.start;
+    while pos < within.end {
+        let header = match parse_header(data, pos) {
+            Some(header) => header,
+            None => break,
+        };
+        let size = match header.size {
+            Some(size) => size as usize,
+            None => break,
+        };
+        let end = pos + header.len + size;
+        if end > within.end {
+            break;
+        }
+        found.push((header.id, pos..end, pos + header.len..end));
+        pos = end;
+    }
+    found
+}
+
+fn read_uint(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |value, byte| (value << 8) | *byte as u64)
+}
+
+fn element(id: u64, payload: &[u8]) -> Vec<u8> {
+    let id_bytes = id.to_be_bytes();
+    let skip = id_bytes.iter().take_while(|byte| **byte == 0).count();
+    let mut encoded = id_bytes[skip..].to_vec();
+    // An eight byte size fits any payload.
+    encoded.push(0x01);
+    encoded.extend_from_slice(&(payload.len() as u64).to_be_bytes()[1..]);
+    encoded.extend_from_slice(payload);
+    encoded
+}
+
+/// Reads the next element header without consuming it.
+async fn peek_header(reader: &mut Reader) -> Result<Option<Header>, String> {
+    // Ids take up to 4 bytes and sizes up to 8; near the end less is buffered.
+    reader.fill(12).await?;
+    if reader.buffered().is_empty() {
+        return Ok(None);
+    }
+    parse_header(reader.buffered(), 0)
+        .map(Some)
+        .ok_or_else(|| "truncated element header".to_owned())
+}
+
+/// Reads the whole element whose header was just peeked.
+async fn read_element(reader: &mut Reader, header: &Header) -> Result<Vec<u8>, String> {
+    let size = header.size.ok_or("element of unknown size")?;
+    reader.read(header.len + size as usize).await
+}
+
+/// Gives a `TrackEntry` a new track number and uid.
+fn renumber_track(entry: &[u8], number: u8) -> Vec<u8> {
+    let header = match parse_header(entry, 0) {
+        Some(header) => header,
+        None => return entry.to_vec(),
+    };
+    let mut payload = vec![];
+    for (id, whole, _) in elements(entry, header.len..entry.len()) {
+        match id {
+            TRACK_NUMBER => payload.extend_from_slice(&element(TRACK_NUMBER, &[number])),
+            TRACK_UID => payload.extend_from_slice(&element(TRACK_UID, &[number])),
+            _ => payload.extend_from_slice(&entry[whole]),
+        }
+    }
+    element(TRACK_ENTRY, &payload)
+}
+
+fn first_track_entry(tracks: &[u8]) -> Result<Vec<u8>, String> {
+    let header = parse_header(tracks, 0).ok_or("empty Tracks")?;
+    elements(tracks, header.len..tracks.len())
+        .into_iter()
+        .find(|(id, _, _)| *id == TRACK_ENTRY)
+        .map(|(_, whole, _)| tracks[whole].to_vec())
+        .ok_or_else(|| "Tracks without a TrackEntry".to_owned())
+}
+
+/// Points every block of a cluster at track `number`. Track numbers below 127 are one
+/// byte long, so the cluster keeps its size.
+fn retag_cluster(cluster: &mut [u8], number: u8) -> Result<(), String> {
+    let header = parse_header(cluster, 0).ok_or("empty Cluster")?;
+    let mut blocks = vec![];
+    for (id, _, payload) in elements(cluster, header.len..cluster.len()) {
+        match id {
+            SIMPLE_BLOCK => blocks.push(payload.start),
+            BLOCK_GROUP => blocks.extend(
+                elements(cluster, payload)
+                    .into_iter()
+                    .filter(|(id, _, _)| *id == BLOCK)
+                    .map(|(_, _, payload)| payload.start),
+            ),
+            _ => {}
+        }
+    }
+    for pos in blocks {
+        match cluster.get_mut(pos) {
+            Some(track) if *track & 0x80 != 0 => *track = 0x80 | number,
+            _ => return Err("block with a multi byte track number".to_owned()),
+        }
+    }
+    Ok(())
+}
+
+struct Cluster {
+    data: Vec<u8>,
+    time: f64,
+}
+
+struct Track {
+    reader: Reader,
+    timecode_scale: u64,
+    next: Option<Cluster>,
+}
+
+impl Track {
+    /// Reads up to the first cluster, returning the EBML header, Info and Tracks.
+    async fn open(mut reader: Reader) -> Result<(Self, Vec<u8>, Vec<u8>, Vec<u8>), String> {
+        let ebml = match peek_header(&mut reader).await? {
+            Some(header) if header.id == EBML => read_element(&mut reader, &header).await?,
+            _ => return Err("not a webm stream".to_owned()),
+        };
+        match peek_header(&mut reader).await? {
+            Some(header) if header.id == SEGMENT => {
+                // Only the header; the Segment's children are read one by one.
+                reader.take(header.len);
+            }
+            _ => return Err("webm without a Segment".to_owned()),
+        }
+        let (mut info, mut tracks) = (None, None);
+        loop {
+            let header = peek_header(&mut reader)
+                .await?
+                .ok_or("webm without clusters")?;
+            match header.id {
+                CLUSTER => break,
+                INFO => info = Some(read_element(&mut reader, &header).await?),
+                TRACKS => tracks = Some(read_element(&mut reader, &header).await?),
+                // SeekHead and Cues point into the source file, so they are dropped.
+                _ => {
+                    read_element(&mut reader, &header).await?;
+                }
+            }
+        }
+        let info = info.ok_or("webm without Info")?;
+        let tracks = tracks.ok_or("webm without Tracks")?;
+        let info_header = parse_header(&info, 0).ok_or("empty Info")?;
+        let timecode_scale = elements(&info, info_header.len..info.len())
+            .into_iter()
+            .find(|(id, _, _)| *id == TIMECODE_SCALE)
+            .map(|(_, _, payload)| read_uint(&info[payload]))
+            .unwrap_or(1_000_000);
+        let track = Track {
+            reader,
+            timecode_scale,
+            next: None,
+        };
+        Ok((track, ebml, info, tracks))
+    }
+
+    async fn advance(&mut self) -> Result<(), String> {
+        self.next = None;
+        loop {
+            let header = match peek_header(&mut self.reader).await? {
+                Some(header) => header,
+                None => return Ok(()),
+            };
+            if header.size.is_none() {
+                return Err("clusters of unknown size are not supported".to_owned());
+            }
+            let data = read_element(&mut self.reader, &header).await?;
+            if header.id != CLUSTER {
+                continue;
+            }
+            let timecode = elements(&data, header.len..data.len())
+                .into_iter()
+                .find(|(id, _, _)| *id == CLUSTER_TIMECODE)
+                .map(|(_, _, payload)| read_uint(&data[payload]))
+                .ok_or("Cluster without a Timecode")?;
+            self.next = Some(Cluster {
+                data,
+                time: timecode as f64 * self.timecode_scale as f64 / 1e9,
+            });
+            return Ok(());
+        }
+    }
+}
+
+/// Muxes two WebM streams into one, sending clusters in time order as they arrive. Only
+/// one cluster per stream is held in memory.
+pub async fn mux(video: Reader, audio: Reader, output: &mut Output) -> Result<(), String> {
+    let (mut video, ebml, info, video_tracks) = Track::open(video).await?;
+    let (mut audio, _, _, audio_tracks) = Track::open(audio).await?;
+    if video.timecode_scale != audio.timecode_scale {
+        return Err("video and audio use different timecode scales".to_owned());
+    }
+    let mut tracks = renumber_track(&first_track_entry(&video_tracks)?, 1);
+    tracks.extend_from_slice(&renumber_track(&first_track_entry(&audio_tracks)?, 2));
+    let mut head = ebml;
+    head.extend_from_slice(&UNSIZED_SEGMENT);
+    head.extend_from_slice(&info);
+    head.extend_from_slice(&element(TRACKS, &tracks));
+    output.send(head).await?;
+
+    video.advance().await?;
+    audio.advance().await?;
+    loop {
+        let video_first = match (&video.next, &audio.next) {
+            (None, None) => return Ok(()),
+            (Some(v), Some(a)) => v.time <= a.time,
+            (v, _) => v.is_some(),
+        };
+        let (track, number) = if video_first {
+            (&mut video, 1)
+        } else {
+            (&mut audio, 2)
+        };
+        if let Some(mut cluster) = track.next.take() {
+            retag_cluster(&mut cluster.data, number)?;
+            output.send(cluster.data).await?;
+        }
+        track.advance().await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::vint;
+
+    #[test]
+    fn one_byte_vint() {
+        assert_eq!(vint(&[0x81], 0, false), Some((Some(1), 1)));
+        assert_eq!(vint(&[0x81], 0, true), Some((Some(0x81), 1)));
+    }
+
+    #[test]
+    fn four_byte_vint() {
+        let id = [0x1A, 0x45, 0xDF, 0xA3];
+        assert_eq!(vint(&id, 0, true), Some((Some(0x1A45_DFA3), 4)));
+        assert_eq!(
+            vint(&[0x10, 0x00, 0x01, 0x00], 0, false),
+            Some((Some(256), 4))
+        );
+    }
+
+    #[test]
+    fn eight_byte_vint() {
+        let size = [0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12, 0x34];
+        assert_eq!(vint(&size, 0, false), Some((Some(0x1234), 8)));
+        assert_eq!(vint(&size[..7], 0, false), None);
+    }
+
+    #[test]
+    fn unknown_size() {
+        assert_eq!(vint(&[0xFF], 0, false), Some((None, 1)));
+        let size = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert_eq!(vint(&size, 0, false), Some((None, 8)));
+        assert_eq!(vint(&[0x00], 0, false), None);
+    }
+}
@@ -0,0 +1,145 @@
+//! Runs the resolvers against the pages recorded under `tests/fixtures`, so extraction
+//! is checked without reaching YouTube. Re-record them after an extractor change with
+//! `DOWNLOADER_MODE=record cargo test --test replay`; until pages are recorded the
+//! tests are skipped.
+
+use juniper::Variables;
+use rusty_pipe_server::serverrunner::{schema, Context, DownloaderObj};
+use serde_json::Value;
+use std::path::Path;
+use std::sync::Once;
+
+const VIDEO_ID: &str = "dQw4w9WgXcQ";
+const CHANNEL_ID: &str = "UC_x5XG1OV2P6uZZ5FSM9Ttw";
+const PLAYLIST_ID: &str = "PLBCF2DAC6FFB574DE";
+const FIXTURE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+static CONFIGURE: Once = Once::new();
+
+/// Points the downloader at the fixtures, replaying them unless `DOWNLOADER_MODE` asks
+/// for something else. The mode is read once, so this has to happen before any test
+/// downloads a page.
+fn configure() {
+    CONFIGURE.call_once(|| {
+        if std::env::var("DOWNLOADER_MODE").is_err() {
+            std::env::set_var("DOWNLOADER_MODE", "replay");
+        }
+        std::env::set_var("FIXTURE_DIR", FIXTURE_DIR);
+    });
+}
+
+/// Whether there is anything to replay, or the run is going to record it.
+fn recorded() -> bool {
+    let replaying =
+        std::env::var("DOWNLOADER_MODE").map_or(true, |mode| mode.eq_ignore_ascii_case("replay"));
+    !replaying
+        || std::fs::read_dir(Path::new(FIXTURE_DIR)).map_or(false, |entries| {
+            entries
+                .filter_map(Result::ok)
+                .any(|entry| entry.path().extension().map_or(false, |ext| ext == "json"))
+        })
+}
+
+/// Runs `query` against the fixtures and returns its data, or `None` when no pages
+/// have been recorded yet.
+async fn run(query: &str) -> Option<Value> {
+    configure();
+    if !recorded() {
+        eprintln!("skipping: no pages recorded under {}", FIXTURE_DIR);
+        return None;
+    }
+    let context = Context {
+        client: None,
+        base_url: "http://localhost:8080".to_owned(),
+        privacy: false,
+    };
+    let (data, errors) = juniper::execute(
+        query,
+        None,
+        &schema::<DownloaderObj>(),
+        &Variables::new(),
+        &context,
+    )
+    .await
+    .expect("query is valid");
+    assert!(errors.is_empty(), "resolver errors: {:?}", errors);
+    Some(serde_json::to_value(&data).unwrap())
+}
+
+/// Runs a query through `run`, returning from the test when there is nothing to replay.
+macro_rules! replay {
+    ($query:expr) => {
+        match run($query).await {
+            Some(data) => data,
+            None => return,
+        }
+    };
+}
+
+fn non_empty(value: &Value, what: &str) {
+    match value {
+        Value::String(s) => assert!(!s.is_empty(), "{} is empty", what),
+        Value::Array(items) => assert!(!items.is_empty(), "{} is empty", what),
+        other => panic!("{} is {}", what, other),
+    }
+}
+
+#[tokio::test]
+async fn video() {
+    let data = replay!(&format!(
+        r#"{{ video(videoId: "{}") {{
+            title uploaderName viewsV2 lengthV2
+            videoStreams {{ itag url mimeType }}
+            audioOnlyStreams {{ itag url }}
+            related {{ ... on VideoResult {{ videoId }} }}
+        }} }}"#,
+        VIDEO_ID
+    ));
+    let video = &data["video"];
+    non_empty(&video["title"], "title");
+    non_empty(&video["videoStreams"], "videoStreams");
+    non_empty(&video["audioOnlyStreams"], "audioOnlyStreams");
+    non_empty(&video["videoStreams"][0]["url"], "stream url");
+    non_empty(&video["related"], "related");
+}
+
+#[tokio::test]
+async fn search() {
+    let data = replay!(
+        r#"{ search(query: "rust programming") {
+            nextPageUrl
+            result {
+                ... on VideoResult { videoId name }
+                ... on ChannelResult { channelId }
+                ... on PlaylistResult { playlistId }
+            }
+        } }"#
+    );
+    non_empty(&data["search"]["result"], "search results");
+}
+
+#[tokio::test]
+async fn channel() {
+    let data = replay!(&format!(
+        r#"{{ channel(channelId: "{}") {{ name avatars {{ url }} videos {{ videoId name }} }} }}"#,
+        CHANNEL_ID
+    ));
+    non_empty(&data["channel"]["name"], "channel name");
+    non_empty(&data["channel"]["videos"], "channel videos");
+}
+
+#[tokio::test]
+async fn playlist() {
+    let data = replay!(&format!(
+        r#"{{ playlist(playlistId: "{}") {{ name uploaderName videosCountV2 videos {{ videoId }} }} }}"#,
+        PLAYLIST_ID
+    ));
+    non_empty(&data["playlist"]["name"], "playlist name");
+    non_empty(&data["playlist"]["videos"], "playlist videos");
+}
+
+#[tokio::test]
+async fn trending() {
+    let data = replay!("{ trending { videos { videoId name viewCountV2 } } }");
+    non_empty(&data["trending"]["videos"], "trending videos");
+}